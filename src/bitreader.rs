@@ -0,0 +1,207 @@
+use std::io;
+use byteorder::ReadBytesExt;
+use error::{Result, ReadContext};
+use crc::{crc8_update, crc16_update};
+
+/// Reads a stream MSB first, a few bits at a time. FLAC frames are only byte
+/// aligned at the header and footer, so the whole frame is read through this.
+///
+/// Running FLAC CRCs are kept over every byte loaded so far.
+pub struct BitReader<'a, R: 'a> {
+    r: &'a mut R,
+    offset: u64,
+    byte: u8,
+    bits_left: u32,
+    crc8: u8,
+    crc16: u16,
+}
+
+impl<'a, R: io::Read> BitReader<'a, R> {
+    /// `offset` is the stream position of `r`, used for error reporting.
+    pub fn new(r: &'a mut R, offset: u64) -> BitReader<'a, R> {
+        BitReader {
+            r: r,
+            offset: offset,
+            byte: 0,
+            bits_left: 0,
+            crc8: 0,
+            crc16: 0,
+        }
+    }
+
+    /// Stream position of the next byte to be loaded.
+    pub fn position(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.bits_left == 0
+    }
+
+    /// CRC-8 of the bytes loaded so far.
+    pub fn crc8(&self) -> u8 {
+        self.crc8
+    }
+
+    /// CRC-16 of the bytes loaded so far.
+    pub fn crc16(&self) -> u16 {
+        self.crc16
+    }
+
+    fn refill(&mut self) -> Result<()> {
+        self.byte = try!(self.r.read_u8().at("FLAC frame", self.offset));
+        self.offset += 1;
+        self.bits_left = 8;
+        self.crc8 = crc8_update(self.crc8, self.byte);
+        self.crc16 = crc16_update(self.crc16, self.byte);
+        Ok(())
+    }
+
+    /// Reads `n` bits, at most 64, as an unsigned value.
+    pub fn read_bits(&mut self, n: u32) -> Result<u64> {
+        debug_assert!(n <= 64);
+        let mut n = n;
+        let mut value: u64 = 0;
+        while n > 0 {
+            if self.bits_left == 0 {
+                try!(self.refill());
+            }
+            let take = if n < self.bits_left { n } else { self.bits_left };
+            let bits = ((self.byte as u64) >> (self.bits_left - take)) & ((1 << take) - 1);
+            value = (value << take) | bits;
+            self.bits_left -= take;
+            n -= take;
+        }
+        Ok(value)
+    }
+
+    /// Reads an `n` bit two's complement value.
+    pub fn read_signed(&mut self, n: u32) -> Result<i64> {
+        let value = try!(self.read_bits(n));
+        if n > 0 && n < 64 && (value >> (n - 1)) & 1 == 1 {
+            Ok(value as i64 - (1i64 << n))
+        } else {
+            Ok(value as i64)
+        }
+    }
+
+    /// Counts zero bits up to and including the terminating one bit.
+    pub fn read_unary(&mut self) -> Result<u32> {
+        let mut count = 0;
+        loop {
+            if self.bits_left == 0 {
+                try!(self.refill());
+            }
+            let rest = (self.byte as u32) & ((1 << self.bits_left) - 1);
+            if rest == 0 {
+                count += self.bits_left;
+                self.bits_left = 0;
+            } else {
+                let zeros = rest.leading_zeros() - (32 - self.bits_left);
+                count += zeros;
+                self.bits_left -= zeros + 1;
+                return Ok(count);
+            }
+        }
+    }
+
+    /// Reads a Rice coded value with parameter `param` and undoes the zigzag
+    /// folding of negative values.
+    pub fn read_rice(&mut self, param: u32) -> Result<i64> {
+        let quotient = try!(self.read_unary()) as u64;
+        let value = (quotient << param) | try!(self.read_bits(param));
+        Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+    }
+
+    /// Skips to the next byte boundary.
+    pub fn align(&mut self) {
+        self.bits_left = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitReader;
+    use error::SurfboardError;
+
+    #[test]
+    fn reads_bits_msb_first() {
+        let data = [0b1010_1100, 0b0101_0011, 0xFF];
+        let mut r = &data[..];
+        let mut bits = BitReader::new(&mut r, 0);
+        assert_eq!(bits.read_bits(1).unwrap(), 1);
+        assert_eq!(bits.read_bits(3).unwrap(), 0b010);
+        assert_eq!(bits.read_bits(8).unwrap(), 0b1100_0101);
+        assert_eq!(bits.read_bits(12).unwrap(), 0b0011_1111_1111);
+        assert!(bits.is_aligned());
+        assert_eq!(bits.position(), 3);
+    }
+
+    #[test]
+    fn reads_signed_values() {
+        let data = [0b1111_0110, 0b0000_0000];
+        let mut r = &data[..];
+        let mut bits = BitReader::new(&mut r, 0);
+        assert_eq!(bits.read_signed(4).unwrap(), -1);
+        assert_eq!(bits.read_signed(3).unwrap(), 3);
+        assert_eq!(bits.read_signed(1).unwrap(), 0);
+        assert_eq!(bits.read_signed(0).unwrap(), 0);
+        assert_eq!(bits.read_signed(8).unwrap(), 0);
+    }
+
+    #[test]
+    fn reads_unary_across_bytes() {
+        let data = [0b0001_0000, 0b0000_0000, 0b0100_0000];
+        let mut r = &data[..];
+        let mut bits = BitReader::new(&mut r, 0);
+        assert_eq!(bits.read_unary().unwrap(), 3);
+        assert_eq!(bits.read_unary().unwrap(), 13);
+        assert_eq!(bits.read_bits(6).unwrap(), 0);
+    }
+
+    #[test]
+    fn reads_rice_values() {
+        // param 2: 0 -> 1|00, -1 -> 1|01, 1 -> 1|10, -3 -> 01|01
+        let data = [0b1001_0111, 0b0010_1000];
+        let mut r = &data[..];
+        let mut bits = BitReader::new(&mut r, 0);
+        assert_eq!(bits.read_rice(2).unwrap(), 0);
+        assert_eq!(bits.read_rice(2).unwrap(), -1);
+        assert_eq!(bits.read_rice(2).unwrap(), 1);
+        assert_eq!(bits.read_rice(2).unwrap(), -3);
+    }
+
+    #[test]
+    fn align_skips_rest_of_byte() {
+        let data = [0b1000_0000, 0xAB];
+        let mut r = &data[..];
+        let mut bits = BitReader::new(&mut r, 0);
+        assert_eq!(bits.read_bits(1).unwrap(), 1);
+        assert!(!bits.is_aligned());
+        bits.align();
+        assert_eq!(bits.read_bits(8).unwrap(), 0xAB);
+    }
+
+    #[test]
+    fn tracks_frame_crcs() {
+        // Header of a one sample, 44.1kHz 16-bit stereo frame
+        let data = [0xFF, 0xF8, 0x69, 0x18, 0x00, 0x00, 0xBF];
+        let mut r = &data[..];
+        let mut bits = BitReader::new(&mut r, 0);
+        bits.read_bits(48).unwrap();
+        assert_eq!(bits.crc8(), 0xBF);
+        bits.read_bits(8).unwrap();
+        assert_eq!(bits.crc8(), 0);
+    }
+
+    #[test]
+    fn reports_truncation_offset() {
+        let data = [0xFF];
+        let mut r = &data[..];
+        let mut bits = BitReader::new(&mut r, 100);
+        match bits.read_bits(16) {
+            Err(SurfboardError::Truncated { offset, .. }) => assert_eq!(offset, 101),
+            other => panic!("expected truncation, got {:?}", other),
+        }
+    }
+}
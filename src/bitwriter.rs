@@ -0,0 +1,113 @@
+/// Builds a byte buffer MSB first, a few bits at a time. The counterpart of
+/// `BitReader`, used to assemble FLAC frames before they are written out.
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> BitWriter {
+        BitWriter {
+            bytes: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Writes the low `n` bits of `value`, at most 32 at a time.
+    pub fn write_bits(&mut self, value: u64, n: u32) {
+        debug_assert!(n <= 32);
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | (value & ((1 << n) - 1));
+        self.bits += n;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    /// Writes `value` as `n` bit two's complement.
+    pub fn write_signed(&mut self, value: i64, n: u32) {
+        self.write_bits(value as u64, n);
+    }
+
+    /// Writes `count` zero bits and a terminating one bit.
+    pub fn write_unary(&mut self, count: u32) {
+        let mut count = count;
+        while count >= 32 {
+            self.write_bits(0, 32);
+            count -= 32;
+        }
+        self.write_bits(1, count + 1);
+    }
+
+    /// Writes `value` Rice coded with parameter `param`, folding negative
+    /// values in zigzag order.
+    pub fn write_rice(&mut self, value: i64, param: u32) {
+        let folded = ((value << 1) ^ (value >> 63)) as u64;
+        self.write_unary((folded >> param) as u32);
+        self.write_bits(folded, param);
+    }
+
+    /// Pads with zero bits up to the next byte boundary.
+    pub fn align(&mut self) {
+        if self.bits > 0 {
+            let pad = 8 - self.bits;
+            self.write_bits(0, pad);
+        }
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.bits == 0
+    }
+
+    /// The bytes written so far, which must end on a byte boundary.
+    pub fn bytes(&self) -> &[u8] {
+        debug_assert!(self.is_aligned());
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        debug_assert!(self.is_aligned());
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitWriter;
+    use bitreader::BitReader;
+
+    #[test]
+    fn writes_bits_msb_first() {
+        let mut bits = BitWriter::new();
+        bits.write_bits(1, 1);
+        bits.write_bits(0b010, 3);
+        bits.write_bits(0b1100_0101, 8);
+        bits.write_bits(0b0011, 4);
+        assert_eq!(bits.into_bytes(), vec![0b1010_1100, 0b0101_0011]);
+    }
+
+    #[test]
+    fn round_trips_through_bit_reader() {
+        let mut bits = BitWriter::new();
+        bits.write_signed(-5, 7);
+        bits.write_unary(40);
+        for &value in [0i64, -1, 1, -3, 1000, -70000].iter() {
+            bits.write_rice(value, 3);
+        }
+        bits.align();
+
+        let data = bits.into_bytes();
+        let mut r = &data[..];
+        let mut reader = BitReader::new(&mut r, 0);
+        assert_eq!(reader.read_signed(7).unwrap(), -5);
+        assert_eq!(reader.read_unary().unwrap(), 40);
+        for &value in [0i64, -1, 1, -3, 1000, -70000].iter() {
+            assert_eq!(reader.read_rice(3).unwrap(), value);
+        }
+    }
+}
@@ -0,0 +1,27 @@
+// CRCs used by FLAC frames, both MSB first with a zero initial value:
+// CRC-8 (x^8 + x^2 + x^1 + x^0) over the frame header and
+// CRC-16 (x^16 + x^15 + x^2 + x^0) over the whole frame.
+
+pub fn crc8_update(crc: u8, byte: u8) -> u8 {
+    let mut crc = crc ^ byte;
+    for _ in 0..8 {
+        crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+    }
+    crc
+}
+
+pub fn crc16_update(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc ^ ((byte as u16) << 8);
+    for _ in 0..8 {
+        crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+    }
+    crc
+}
+
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| crc8_update(crc, byte))
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| crc16_update(crc, byte))
+}
@@ -0,0 +1,153 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::result;
+use byteorder;
+
+/// Everything that can go wrong while reading or writing a WAV or FLAC stream.
+///
+/// Offsets are absolute byte positions in the stream, pointing at the start of
+/// the chunk, block or frame being parsed when the error occurred.
+#[derive(Debug)]
+pub enum SurfboardError {
+    /// The underlying reader or writer failed.
+    Io(io::Error),
+    /// A magic number or chunk id was not the one expected.
+    BadMagic { offset: u64, expected: &'static str, found: String },
+    /// A format code is reserved or not supported by Surfboard.
+    Unsupported { offset: u64, what: &'static str, code: u32 },
+    /// The stream ended before a chunk, block or frame was complete.
+    Truncated { offset: u64, what: &'static str },
+    /// A stored checksum does not match the data it covers.
+    CrcMismatch { offset: u64, what: &'static str, expected: u32, computed: u32 },
+    /// The data is structurally invalid.
+    Malformed { offset: u64, what: &'static str },
+    /// A seek asked for a sample at or past the end of a stream of `total` samples.
+    SeekPastEnd { sample: u64, total: u64 },
+}
+
+pub type Result<T> = result::Result<T, SurfboardError>;
+
+impl fmt::Display for SurfboardError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SurfboardError::Io(ref err) => write!(f, "I/O error: {}", err),
+            SurfboardError::BadMagic { offset, expected, ref found } =>
+                write!(f, "expected {:?} at offset {}, found {:?}", expected, offset, found),
+            SurfboardError::Unsupported { offset, what, code } =>
+                write!(f, "unsupported {} {} at offset {}", what, code, offset),
+            SurfboardError::Truncated { offset, what } =>
+                write!(f, "truncated {} at offset {}", what, offset),
+            SurfboardError::CrcMismatch { offset, what, expected, computed } =>
+                write!(f, "{} mismatch at offset {}: stored {:#x}, computed {:#x}", what, offset, expected, computed),
+            SurfboardError::Malformed { offset, what } =>
+                write!(f, "malformed {} at offset {}", what, offset),
+            SurfboardError::SeekPastEnd { sample, total } =>
+                write!(f, "cannot seek to sample {} of a {} sample stream", sample, total),
+        }
+    }
+}
+
+impl error::Error for SurfboardError {
+    fn description(&self) -> &str {
+        match *self {
+            SurfboardError::Io(ref err) => err.description(),
+            SurfboardError::BadMagic { .. } => "bad magic number",
+            SurfboardError::Unsupported { .. } => "unsupported format code",
+            SurfboardError::Truncated { .. } => "truncated stream",
+            SurfboardError::CrcMismatch { .. } => "checksum mismatch",
+            SurfboardError::Malformed { .. } => "malformed data",
+            SurfboardError::SeekPastEnd { .. } => "seek past the end of the stream",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            SurfboardError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SurfboardError {
+    fn from(err: io::Error) -> SurfboardError {
+        SurfboardError::Io(err)
+    }
+}
+
+impl From<byteorder::Error> for SurfboardError {
+    fn from(err: byteorder::Error) -> SurfboardError {
+        SurfboardError::Io(err.into())
+    }
+}
+
+/// Tags a failed read with the chunk being parsed, turning EOF into `Truncated`.
+pub trait ReadContext<T> {
+    fn at(self, what: &'static str, offset: u64) -> Result<T>;
+}
+
+impl<T> ReadContext<T> for byteorder::Result<T> {
+    fn at(self, what: &'static str, offset: u64) -> Result<T> {
+        match self {
+            Ok(v) => Ok(v),
+            Err(byteorder::Error::UnexpectedEOF) => Err(SurfboardError::Truncated { offset: offset, what: what }),
+            Err(byteorder::Error::Io(err)) => Err(err).at(what, offset),
+        }
+    }
+}
+
+impl<T> ReadContext<T> for io::Result<T> {
+    fn at(self, what: &'static str, offset: u64) -> Result<T> {
+        match self {
+            Ok(v) => Ok(v),
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof =>
+                Err(SurfboardError::Truncated { offset: offset, what: what }),
+            Err(err) => Err(SurfboardError::Io(err)),
+        }
+    }
+}
+
+/// Current position of `s`, used to give errors an offset.
+pub fn tell<S: io::Seek>(s: &mut S) -> Result<u64> {
+    Ok(try!(s.seek(io::SeekFrom::Current(0))))
+}
+
+/// Reads a four byte chunk or block id.
+pub fn read_id<R: io::Read>(r: &mut R, what: &'static str, offset: u64) -> Result<[u8; 4]> {
+    let mut id = [0; 4];
+    try!(r.read_exact(&mut id).at(what, offset));
+    Ok(id)
+}
+
+/// Reads a four byte id and checks it against `expected`.
+pub fn expect_id<R: io::Read>(r: &mut R, expected: &'static str, offset: u64) -> Result<()> {
+    let id = try!(read_id(r, expected, offset));
+    if &id[..] != expected.as_bytes() {
+        return Err(SurfboardError::BadMagic {
+            offset: offset,
+            expected: expected,
+            found: String::from_utf8_lossy(&id).into_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Reads exactly `length` bytes.
+pub fn read_bytes<R: io::Read>(r: &mut R, length: u32, what: &'static str, offset: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    {
+        let mut handle = r.take(length as u64);
+        try!(handle.read_to_end(&mut bytes));
+    }
+    if bytes.len() < length as usize {
+        return Err(SurfboardError::Truncated { offset: offset, what: what });
+    }
+    Ok(bytes)
+}
+
+/// Reads `length` bytes of UTF-8 text.
+pub fn read_string<R: io::Read>(r: &mut R, length: u32, what: &'static str, offset: u64) -> Result<String> {
+    let string_v = try!(read_bytes(r, length, what, offset));
+    String::from_utf8(string_v).map_err(|_| SurfboardError::Malformed { offset: offset, what: "UTF-8 string" })
+}
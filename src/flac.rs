@@ -1,25 +1,44 @@
 // Based on https://xiph.org/flac/format.html
 
-#![allow(dead_code)]
-
-use std::io::prelude::*;
 use std::io;
+use std::cmp;
 use num::bigint::BigUint;
-use byteorder::{LittleEndian, BigEndian, ByteOrder, ReadBytesExt};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use error::{SurfboardError, Result, ReadContext, tell, expect_id, read_bytes, read_string};
+use bitreader::BitReader;
+use crc::crc16;
+use md5::Md5;
+use vorbis::VorbisComments;
 
 #[derive(Debug, Clone)]
-enum BlockName {
+pub enum BlockName {
     StreamInfo,
     Padding,
     Application,
     Seektable,
     VorbisComment,
+    CueSheet,
     Picture,
     Other,
 }
 
+impl BlockName {
+    fn from_code(code: u8) -> BlockName {
+        match code {
+            0 => BlockName::StreamInfo,
+            1 => BlockName::Padding,
+            2 => BlockName::Application,
+            3 => BlockName::Seektable,
+            4 => BlockName::VorbisComment,
+            5 => BlockName::CueSheet,
+            6 => BlockName::Picture,
+            _ => BlockName::Other,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-enum BlockType {
+pub enum BlockType {
     StreamInfo {
         min_block_size: u16,
         max_block_size: u16,
@@ -31,27 +50,32 @@ enum BlockType {
         total_samples: u64,
         sig: BigUint,
     },
+    /// Number of padding bytes.
     Padding(u64),
-    Application,
-    Seektable,
-    VorbisComment {
-        vendor_string: String,
-        comments: Vec<String>,
+    Application {
+        /// Registered application ID.
+        id: [u8; 4],
+        data: Vec<u8>,
+    },
+    Seektable(Vec<SeekPoint>),
+    VorbisComment(VorbisComments),
+    CueSheet(CueSheet),
+    Picture(Picture),
+    /// A reserved block type, kept as is so it can be written back.
+    Other {
+        code: u8,
+        data: Vec<u8>,
     },
-    Picture,
-    Other,
 }
 
 impl BlockType {
-    fn stream<R: io::Read + io::Seek>(r: &mut R) -> BlockType {
-        let min_block_size = r.read_u16::<BigEndian>().unwrap();
-        let max_block_size = r.read_u16::<BigEndian>().unwrap();
+    fn stream<R: io::Read + io::Seek>(r: &mut R) -> Result<BlockType> {
+        let offset = try!(tell(r));
+        let min_block_size = try!(r.read_u16::<BigEndian>().at("STREAMINFO block", offset));
+        let max_block_size = try!(r.read_u16::<BigEndian>().at("STREAMINFO block", offset));
 
-        let mut frame_size_v = Vec::new();
-        {
-            let mut data_handle = r.take(6);
-            data_handle.read_to_end(&mut frame_size_v).unwrap();
-        }
+        let mut frame_size_v = vec![0; 6];
+        try!(r.read_exact(&mut frame_size_v).at("STREAMINFO block", offset));
 
         let mut t_max = frame_size_v.split_off(3);
         frame_size_v.reverse();
@@ -66,31 +90,18 @@ impl BlockType {
             max_frame_size += (*byte as u32) << (8 * i);
         }
 
-        let stream_data = r.read_u64::<BigEndian>().unwrap();
+        let stream_data = try!(r.read_u64::<BigEndian>().at("STREAMINFO block", offset));
         let sample_rate: u32 = (stream_data >> 44) as u32; // 20 bits
         let num_channels: u8 = (((stream_data << 20) >> 61) + 1) as u8; // 3 bits
         let bits_per_sample: u8 = (((stream_data << 23) >> 59) + 1) as u8; // 5 bits
         let total_samples: u64 = ((stream_data << 36) >> 36) as u64; //36 bits
 
-        let mut sig_v = Vec::new();
-        {
-            let mut data_handle = r.take(16);
-            data_handle.read_to_end(&mut sig_v).unwrap();
-        }
+        let mut sig_v = vec![0; 16];
+        try!(r.read_exact(&mut sig_v).at("STREAMINFO block", offset));
 
         let sig = BigUint::from_bytes_be(&*sig_v);
 
-        println!("min blocksize: {} samples", min_block_size);
-        println!("max blocksize: {} samples", max_block_size);
-        println!("min framesize: {} bytes", min_frame_size);
-        println!("max framesize: {} bytes", max_frame_size);
-        println!("sample rate: {}", sample_rate);
-        println!("number of channels: {}", num_channels);
-        println!("bits per sample: {}", bits_per_sample);
-        println!("total samples: {}", total_samples);
-        println!("md5 sig: {:x}", sig);
-
-        BlockType::StreamInfo {
+        Ok(BlockType::StreamInfo {
             min_block_size: min_block_size,
             max_block_size: max_block_size,
             min_frame_size: min_frame_size,
@@ -100,106 +111,431 @@ impl BlockType {
             bits_per_sample: bits_per_sample,
             total_samples: total_samples,
             sig: sig,
-        }
+        })
     }
 
-    fn pad<R: io::Read + io::Seek>(r: &mut R) -> BlockType { BlockType::Other }
-    fn app<R: io::Read + io::Seek>(r: &mut R) -> BlockType { BlockType::Other }
-    fn table<R: io::Read + io::Seek>(r: &mut R) -> BlockType { BlockType::Other }
-    fn comment<R: io::Read + io::Seek>(r: &mut R) -> BlockType {
-        let vendor_length = r.read_u32::<LittleEndian>().unwrap();
-        let mut vendor_string = String::new();
-        {
-            let mut string_handle = r.take(vendor_length as u64);
-            string_handle.read_to_string(&mut vendor_string).unwrap();
+    fn pad<R: io::Read + io::Seek>(r: &mut R, length: u32) -> Result<BlockType> {
+        try!(r.seek(io::SeekFrom::Current(length as i64)));
+        Ok(BlockType::Padding(length as u64))
+    }
+    fn app<R: io::Read + io::Seek>(r: &mut R, length: u32) -> Result<BlockType> {
+        let offset = try!(tell(r));
+        if length < 4 {
+            return Err(SurfboardError::Malformed { offset: offset, what: "APPLICATION block length" });
+        }
+        let mut id = [0; 4];
+        try!(r.read_exact(&mut id).at("APPLICATION block", offset));
+        let data = try!(read_bytes(r, length - 4, "APPLICATION block", offset));
+
+        Ok(BlockType::Application {
+            id: id,
+            data: data,
+        })
+    }
+    fn other<R: io::Read + io::Seek>(r: &mut R, code: u8, length: u32) -> Result<BlockType> {
+        let offset = try!(tell(r));
+        let data = try!(read_bytes(r, length, "metadata block", offset));
+
+        Ok(BlockType::Other {
+            code: code,
+            data: data,
+        })
+    }
+    fn table<R: io::Read + io::Seek>(r: &mut R, length: u32) -> Result<BlockType> {
+        let offset = try!(tell(r));
+        if length % 18 != 0 {
+            return Err(SurfboardError::Malformed { offset: offset, what: "SEEKTABLE block length" });
         }
 
-        let comment_list_length = r.read_u32::<LittleEndian>().unwrap();
+        let mut points = Vec::new();
+        for _ in 0..length / 18 {
+            let sample_number = try!(r.read_u64::<BigEndian>().at("SEEKTABLE block", offset));
+            let stream_offset = try!(r.read_u64::<BigEndian>().at("SEEKTABLE block", offset));
+            let frame_samples = try!(r.read_u16::<BigEndian>().at("SEEKTABLE block", offset));
+            points.push(SeekPoint {
+                sample_number: sample_number,
+                stream_offset: stream_offset,
+                frame_samples: frame_samples,
+            });
+        }
 
-        let mut comment_list = Vec::new();
-        for i in 0..comment_list_length {
-            let comment_length = r.read_u32::<LittleEndian>().unwrap();
-            let mut comment_string = String::new();
-            {
-                let mut string_handle = r.take(comment_length as u64);
-                string_handle.read_to_string(&mut comment_string).unwrap();
+        Ok(BlockType::Seektable(points))
+    }
+    fn comment<R: io::Read + io::Seek>(r: &mut R) -> Result<BlockType> {
+        Ok(BlockType::VorbisComment(try!(VorbisComments::parse(r))))
+    }
+    fn cue<R: io::Read + io::Seek>(r: &mut R) -> Result<BlockType> {
+        let offset = try!(tell(r));
+        let mut catalog = [0; 128];
+        try!(r.read_exact(&mut catalog).at("CUESHEET block", offset));
+        let lead_in = try!(r.read_u64::<BigEndian>().at("CUESHEET block", offset));
+        let mut flags = [0; 259];
+        try!(r.read_exact(&mut flags).at("CUESHEET block", offset));
+        let num_tracks = try!(r.read_u8().at("CUESHEET block", offset));
+
+        let mut tracks = Vec::new();
+        for _ in 0..num_tracks {
+            let track_offset = try!(r.read_u64::<BigEndian>().at("CUESHEET track", offset));
+            let number = try!(r.read_u8().at("CUESHEET track", offset));
+            let mut isrc = [0; 12];
+            try!(r.read_exact(&mut isrc).at("CUESHEET track", offset));
+            let mut track_flags = [0; 14];
+            try!(r.read_exact(&mut track_flags).at("CUESHEET track", offset));
+            let num_indices = try!(r.read_u8().at("CUESHEET track", offset));
+
+            let mut indices = Vec::new();
+            for _ in 0..num_indices {
+                let index_offset = try!(r.read_u64::<BigEndian>().at("CUESHEET index", offset));
+                let index_number = try!(r.read_u8().at("CUESHEET index", offset));
+                let mut reserved = [0; 3];
+                try!(r.read_exact(&mut reserved).at("CUESHEET index", offset));
+                indices.push(CueIndex {
+                    offset: index_offset,
+                    number: index_number,
+                });
             }
 
-            comment_list.push(comment_string);
+            tracks.push(CueTrack {
+                offset: track_offset,
+                number: number,
+                isrc: nul_padded(&isrc),
+                is_audio: track_flags[0] & 0x80 == 0,
+                pre_emphasis: track_flags[0] & 0x40 != 0,
+                indices: indices,
+            });
         }
 
-        println!("vendor string: {}", vendor_string);
-        println!("comment list: {:?}", comment_list);
+        Ok(BlockType::CueSheet(CueSheet {
+            media_catalog_number: nul_padded(&catalog),
+            lead_in: lead_in,
+            is_cd: flags[0] & 0x80 != 0,
+            tracks: tracks,
+        }))
+    }
+    /// Block type code in the metadata block header.
+    pub fn code(&self) -> u8 {
+        match *self {
+            BlockType::StreamInfo { .. } => 0,
+            BlockType::Padding(_) => 1,
+            BlockType::Application { .. } => 2,
+            BlockType::Seektable(_) => 3,
+            BlockType::VorbisComment(_) => 4,
+            BlockType::CueSheet(_) => 5,
+            BlockType::Picture(_) => 6,
+            BlockType::Other { code, .. } => code,
+        }
+    }
 
-        BlockType::VorbisComment {
-            vendor_string: vendor_string,
-            comments: comment_list,
+    /// Writes the block payload, without the block header.
+    pub fn write<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        match *self {
+            BlockType::StreamInfo { min_block_size, max_block_size, min_frame_size, max_frame_size,
+                                    sample_rate, num_channels, bits_per_sample, total_samples, ref sig } => {
+                try!(w.write_u16::<BigEndian>(min_block_size));
+                try!(w.write_u16::<BigEndian>(max_block_size));
+                try!(w.write_uint::<BigEndian>(min_frame_size as u64, 3));
+                try!(w.write_uint::<BigEndian>(max_frame_size as u64, 3));
+                let stream_data = (sample_rate as u64) << 44 | ((num_channels - 1) as u64) << 41 |
+                                  ((bits_per_sample - 1) as u64) << 36 | total_samples;
+                try!(w.write_u64::<BigEndian>(stream_data));
+                try!(w.write_all(&sig_bytes(sig)));
+            },
+            BlockType::Padding(length) => {
+                try!(w.write_all(&vec![0; length as usize]));
+            },
+            BlockType::Application { ref id, ref data } => {
+                try!(w.write_all(id));
+                try!(w.write_all(data));
+            },
+            BlockType::Seektable(ref points) => {
+                for point in points.iter() {
+                    try!(w.write_u64::<BigEndian>(point.sample_number));
+                    try!(w.write_u64::<BigEndian>(point.stream_offset));
+                    try!(w.write_u16::<BigEndian>(point.frame_samples));
+                }
+            },
+            BlockType::VorbisComment(ref comments) => {
+                try!(comments.write(w));
+            },
+            BlockType::CueSheet(ref cue) => {
+                try!(write_nul_padded(w, &cue.media_catalog_number, 128));
+                try!(w.write_u64::<BigEndian>(cue.lead_in));
+                let mut flags = [0; 259];
+                flags[0] = if cue.is_cd { 0x80 } else { 0 };
+                try!(w.write_all(&flags));
+                try!(w.write_u8(cue.tracks.len() as u8));
+                for track in cue.tracks.iter() {
+                    try!(w.write_u64::<BigEndian>(track.offset));
+                    try!(w.write_u8(track.number));
+                    try!(write_nul_padded(w, &track.isrc, 12));
+                    let mut track_flags = [0; 14];
+                    track_flags[0] = if track.is_audio { 0 } else { 0x80 } | if track.pre_emphasis { 0x40 } else { 0 };
+                    try!(w.write_all(&track_flags));
+                    try!(w.write_u8(track.indices.len() as u8));
+                    for index in track.indices.iter() {
+                        try!(w.write_u64::<BigEndian>(index.offset));
+                        try!(w.write_u8(index.number));
+                        try!(w.write_all(&[0; 3]));
+                    }
+                }
+            },
+            BlockType::Picture(ref picture) => {
+                try!(w.write_u32::<BigEndian>(picture.picture_type));
+                try!(w.write_u32::<BigEndian>(picture.mime_type.len() as u32));
+                try!(w.write_all(picture.mime_type.as_bytes()));
+                try!(w.write_u32::<BigEndian>(picture.description.len() as u32));
+                try!(w.write_all(picture.description.as_bytes()));
+                try!(w.write_u32::<BigEndian>(picture.width));
+                try!(w.write_u32::<BigEndian>(picture.height));
+                try!(w.write_u32::<BigEndian>(picture.depth));
+                try!(w.write_u32::<BigEndian>(picture.colors));
+                try!(w.write_u32::<BigEndian>(picture.data.len() as u32));
+                try!(w.write_all(&picture.data));
+            },
+            BlockType::Other { ref data, .. } => {
+                try!(w.write_all(data));
+            },
         }
+        Ok(())
+    }
+
+    fn picture<R: io::Read + io::Seek>(r: &mut R) -> Result<BlockType> {
+        let offset = try!(tell(r));
+        let picture_type = try!(r.read_u32::<BigEndian>().at("PICTURE block", offset));
+        let mime_length = try!(r.read_u32::<BigEndian>().at("PICTURE block", offset));
+        let mime_type = try!(read_string(r, mime_length, "PICTURE block", offset));
+        let description_length = try!(r.read_u32::<BigEndian>().at("PICTURE block", offset));
+        let description = try!(read_string(r, description_length, "PICTURE block", offset));
+        let width = try!(r.read_u32::<BigEndian>().at("PICTURE block", offset));
+        let height = try!(r.read_u32::<BigEndian>().at("PICTURE block", offset));
+        let depth = try!(r.read_u32::<BigEndian>().at("PICTURE block", offset));
+        let colors = try!(r.read_u32::<BigEndian>().at("PICTURE block", offset));
+        let data_length = try!(r.read_u32::<BigEndian>().at("PICTURE block", offset));
+        let data = try!(read_bytes(r, data_length, "PICTURE block", offset));
+
+        Ok(BlockType::Picture(Picture {
+            picture_type: picture_type,
+            mime_type: mime_type,
+            description: description,
+            width: width,
+            height: height,
+            depth: depth,
+            colors: colors,
+            data: data,
+        }))
+    }
+}
+
+/// The MD5 signature as stored in STREAMINFO. `BigUint` drops leading zero
+/// bytes, so they are put back here.
+fn sig_bytes(sig: &BigUint) -> [u8; 16] {
+    let stored = sig.to_bytes_be();
+    let mut bytes = [0; 16];
+    let start = 16 - cmp::min(stored.len(), 16);
+    for (dst, src) in bytes[start..].iter_mut().zip(stored.iter()) {
+        *dst = *src;
+    }
+    bytes
+}
+
+/// Writes `text` into a fixed size field, padding it with NUL bytes.
+fn write_nul_padded<W: io::Write>(w: &mut W, text: &str, size: usize) -> Result<()> {
+    let mut field = vec![0; size];
+    for (dst, src) in field.iter_mut().zip(text.as_bytes()) {
+        *dst = *src;
+    }
+    try!(w.write_all(&field));
+    Ok(())
+}
+
+/// One entry of a SEEKTABLE block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SeekPoint {
+    /// First sample of the target frame.
+    pub sample_number: u64,
+    /// Byte offset of the target frame from the first frame header.
+    pub stream_offset: u64,
+    pub frame_samples: u16,
+}
+
+impl SeekPoint {
+    /// Placeholder points reserve space for later and point nowhere.
+    pub fn is_placeholder(&self) -> bool {
+        self.sample_number == 0xFFFFFFFFFFFFFFFF
     }
-    fn picture<R: io::Read + io::Seek>(r: &mut R) -> BlockType { BlockType::Other }
+}
+
+/// A CUESHEET block, describing the tracks of a CD or other medium.
+#[derive(Debug, Clone)]
+pub struct CueSheet {
+    pub media_catalog_number: String,
+    /// Samples before the first index point of the first track.
+    pub lead_in: u64,
+    pub is_cd: bool,
+    /// The last track is the lead-out.
+    pub tracks: Vec<CueTrack>,
 }
 
 #[derive(Debug, Clone)]
-struct Block {
-    last_meta: bool,
-    block_name: BlockName,
-    length: u32,
-    type_data: Option<BlockType>,
+pub struct CueTrack {
+    /// Offset in samples from the start of the audio.
+    pub offset: u64,
+    pub number: u8,
+    pub isrc: String,
+    pub is_audio: bool,
+    pub pre_emphasis: bool,
+    pub indices: Vec<CueIndex>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CueIndex {
+    /// Offset in samples from the track offset.
+    pub offset: u64,
+    pub number: u8,
+}
+
+/// Text stored in a fixed size field, padded with NUL bytes.
+fn nul_padded(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// An embedded image, such as cover art.
+#[derive(Debug, Clone)]
+pub struct Picture {
+    /// ID3v2 APIC picture type, see `type_name`.
+    pub picture_type: u32,
+    /// `-->` means `data` is a URL rather than the image itself.
+    pub mime_type: String,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    /// Bits per pixel.
+    pub depth: u32,
+    /// Palette size for indexed images, 0 otherwise.
+    pub colors: u32,
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    pub fn type_name(&self) -> &'static str {
+        match self.picture_type {
+            0 => "other",
+            1 => "file icon",
+            2 => "other file icon",
+            3 => "front cover",
+            4 => "back cover",
+            5 => "leaflet page",
+            6 => "media",
+            7 => "lead artist",
+            8 => "artist",
+            9 => "conductor",
+            10 => "band",
+            11 => "composer",
+            12 => "lyricist",
+            13 => "recording location",
+            14 => "during recording",
+            15 => "during performance",
+            16 => "movie screen capture",
+            17 => "bright coloured fish",
+            18 => "illustration",
+            19 => "band logotype",
+            20 => "publisher logotype",
+            _ => "reserved",
+        }
+    }
+
+    /// Whether `data` holds a URL to the image instead of the image.
+    pub fn is_link(&self) -> bool {
+        self.mime_type == "-->"
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub last_meta: bool,
+    pub block_name: BlockName,
+    pub length: u32,
+    pub type_data: Option<BlockType>,
 }
 
 impl Block {
-    fn parse<R: io::Read + io::Seek>(r: &mut R) -> Block {
-        let header = r.read_u32::<BigEndian>().unwrap();
+    /// Wraps `type_data` in a block, working out its name and length.
+    pub fn new(type_data: BlockType) -> Result<Block> {
+        let mut payload = Vec::new();
+        try!(type_data.write(&mut payload));
+        if payload.len() >= 1 << 24 {
+            return Err(SurfboardError::Malformed { offset: 0, what: "metadata block over 16 MiB" });
+        }
 
-        let last_meta = match header >> 31 {
-            0 => false,
-            1 => true,
-            _ => panic!("wut"),
-        };
+        Ok(Block {
+            last_meta: false,
+            block_name: BlockName::from_code(type_data.code()),
+            length: payload.len() as u32,
+            type_data: Some(type_data),
+        })
+    }
 
-        let block_name = match (header << 1) >> 25 {
-            0 => BlockName::StreamInfo,
-            1 => BlockName::Padding,
-            2 => BlockName::Application,
-            3 => BlockName::Seektable,
-            4 => BlockName::VorbisComment,
-            5 => BlockName::Picture,
-            _ => BlockName::Other,
+    /// Writes the block header and payload. `last` marks the final metadata
+    /// block before the audio frames.
+    pub fn write<W: io::Write>(&self, w: &mut W, last: bool) -> Result<()> {
+        let type_data = match self.type_data {
+            Some(ref type_data) => type_data,
+            None => return Err(SurfboardError::Malformed { offset: 0, what: "metadata block without data" }),
         };
+        let header = (if last { 1 << 31 } else { 0 }) | (type_data.code() as u32) << 24 | self.length;
+        try!(w.write_u32::<BigEndian>(header));
+        type_data.write(w)
+    }
 
-        let length = (header << 8) >> 8;
+    pub fn parse<R: io::Read + io::Seek>(r: &mut R) -> Result<Block> {
+        let offset = try!(tell(r));
+        let header = try!(r.read_u32::<BigEndian>().at("metadata block header", offset));
+
+        let last_meta = header >> 31 == 1;
 
-        println!("\nLast block before audio? {}", last_meta);
-        println!("{:?}", block_name);
-        println!("-----------------------------");
+        let code = ((header << 1) >> 25) as u8;
+        if code == 127 {
+            return Err(SurfboardError::Unsupported { offset: offset, what: "metadata block type", code: 127 });
+        }
+
+        let block_name = BlockName::from_code(code);
+
+        let length = (header << 8) >> 8;
 
         let type_data = match block_name {
-            BlockName::StreamInfo => Some(BlockType::stream(r)),
-            BlockName::Padding => Some(BlockType::pad(r)),
-            BlockName::Application => Some(BlockType::app(r)),
-            BlockName::Seektable => Some(BlockType::table(r)),
-            BlockName::VorbisComment => Some(BlockType::comment(r)),
-            BlockName::Picture => Some(BlockType::picture(r)),
-            BlockName::Other => None,
+            BlockName::StreamInfo => Some(try!(BlockType::stream(r))),
+            BlockName::Padding => Some(try!(BlockType::pad(r, length))),
+            BlockName::Application => Some(try!(BlockType::app(r, length))),
+            BlockName::Seektable => Some(try!(BlockType::table(r, length))),
+            BlockName::VorbisComment => Some(try!(BlockType::comment(r))),
+            BlockName::CueSheet => Some(try!(BlockType::cue(r))),
+            BlockName::Picture => Some(try!(BlockType::picture(r))),
+            BlockName::Other => Some(try!(BlockType::other(r, code, length))),
         };
 
-        Block {
+        // Every parser must stop exactly at the next block header
+        if try!(tell(r)) != offset + 4 + length as u64 {
+            return Err(SurfboardError::Malformed { offset: offset, what: "metadata block length" });
+        }
+
+        Ok(Block {
             last_meta: last_meta,
             block_name: block_name,
             length: length,
             type_data: type_data,
-        }
+        })
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum BlockStrategy {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlockStrategy {
     FixedBlocksize,
     VariableBlocksize,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
-enum Channels {
+pub enum Channels {
     Mono,
     LR,
     LRC,
@@ -214,7 +550,7 @@ enum Channels {
 }
 
 impl Channels {
-    fn channel_num(c: Channels) -> u8 {
+    pub fn channel_num(c: Channels) -> u8 {
         match c {
             Channels::Mono => 1,
             Channels::LR => 2,
@@ -231,88 +567,86 @@ impl Channels {
     }
 }
 
-fn decode_utf8_val<R: io::Read + io::Seek>(r: &mut R, s: &BlockStrategy) -> u64 {
-    let byte_0 = r.read_u8().unwrap();
-    let mut magic_string_v = vec![];
-    if String::from_utf8(magic_string_v.clone()).is_err() {
-        panic!("Can't decode as UTF8");
-    } else {
-        magic_string_v.push(byte_0);
-    }
+/// The frame or sample number is stored like a UTF-8 code point: the number
+/// of leading one bits in the first byte gives the length of the sequence,
+/// and every following byte carries six bits as `10xxxxxx`. Frame numbers
+/// take up to 6 bytes (31 bits), sample numbers up to 7 bytes (36 bits).
+fn decode_utf8_val<R: io::Read>(r: &mut BitReader<R>, s: &BlockStrategy, offset: u64) -> Result<u64> {
+    let byte_0 = try!(r.read_bits(8));
 
-    let mut value = 0;
-    let mut magic_clone_v = magic_string_v.clone();
+    let length = (!(byte_0 as u8)).leading_zeros();
+    let limit = if *s == BlockStrategy::VariableBlocksize { 7 } else { 6 };
 
-    let mut limit = 0;
-    if *s == BlockStrategy::VariableBlocksize {
-        limit = 7;
-    } else {
-        limit = 6;
+    if length == 0 {
+        return Ok(byte_0);
+    }
+    if length == 1 || length > limit {
+        return Err(SurfboardError::Malformed { offset: offset, what: "coded frame number" });
     }
 
-    for i in 0..limit {
-        {
-            let mut string_handle = r.take(1);
-            string_handle.read_to_end(&mut magic_string_v).unwrap();
-        }
-
-        if String::from_utf8(magic_string_v.clone()).is_err() {
-            let value_v = magic_clone_v.clone();
-            for c in value_v.iter() {
-                value |= (*c as u64) & 0x3F;
-            }
-            r.seek(io::SeekFrom::Current(-1));
-            return value;
-        } else {
-            magic_clone_v = magic_string_v.clone();
+    let mut value = byte_0 & (0x7F >> length);
+    for _ in 1..length {
+        let byte = try!(r.read_bits(8));
+        if byte & 0xC0 != 0x80 {
+            return Err(SurfboardError::Malformed { offset: offset, what: "coded frame number" });
         }
+        value = (value << 6) | (byte & 0x3F);
     }
-    return value;
+    Ok(value)
 }
 
-struct FrameHeader {
-    sync_code: u16,
-    block_strategy: BlockStrategy,
-    block_size: u16,
-    sample_rate: u32,
-    channel_val: Channels,
-    sample_size: u8,
-    crc_8: u8,
+#[derive(Debug)]
+pub struct FrameHeader {
+    pub sync_code: u16,
+    pub block_strategy: BlockStrategy,
+    /// Set for fixed blocksize streams.
+    pub frame_number: Option<u64>,
+    /// Set for variable blocksize streams.
+    pub first_sample_number: Option<u64>,
+    pub block_size: u16,
+    pub sample_rate: u32,
+    pub channel_val: Channels,
+    pub sample_size: u8,
+    pub crc_8: u8,
 }
 
 impl FrameHeader {
-    pub fn parse<R: io::Read + io::Seek>(r: &mut R, rate: u32, bps: u8) -> FrameHeader {
-        let header = r.read_u32::<BigEndian>().unwrap();
-
-        let sync_code = (header >> 18) as u16;
-        let block_strategy = match (header << 19) >> 31 {
-            0 => BlockStrategy::FixedBlocksize,
-            1 => BlockStrategy::VariableBlocksize,
-            _ => panic!("wut"),
-        };
+    /// Number of the first sample in this frame. Fixed blocksize streams
+    /// only count frames, so `block_size` is the stream's blocksize.
+    pub fn first_sample(&self, block_size: u16) -> u64 {
+        match (self.first_sample_number, self.frame_number) {
+            (Some(sample), _) => sample,
+            (None, Some(frame)) => frame * block_size as u64,
+            (None, None) => 0,
+        }
+    }
 
-        let block_size_bits =  (header << 16) >> 28;
-        let sample_rate_bits = (header << 20) >> 28;
-        let channel_val_bits = (header << 24) >> 28;
-        let sample_size_bits = (header << 27) >> 29;
+    /// With `verify` set, a header whose CRC-8 does not match is an error.
+    pub fn parse<R: io::Read>(r: &mut BitReader<R>, rate: u32, bps: u8, verify: bool) -> Result<FrameHeader> {
+        let offset = r.position();
 
-        let sample_rate = match sample_rate_bits {
-            0 => rate,
-            1 => 88200,
-            2 => 176400,
-            3 => 192000,
-            4 => 8000,
-            5 => 16000,
-            6 => 22050,
-            7 => 24000,
-            8 => 32000,
-            9 => 44100,
-            10 => 48000,
-            11 => 96000,
-            15 => panic!("invalid rate!"),
-            _ => 0,
+        let sync_code = try!(r.read_bits(14)) as u16;
+        if sync_code != 0x3FFE {
+            return Err(SurfboardError::Malformed { offset: offset, what: "frame sync code" });
+        }
+        if try!(r.read_bits(1)) != 0 {
+            return Err(SurfboardError::Malformed { offset: offset, what: "frame header reserved bit" });
+        }
+
+        let block_strategy = if try!(r.read_bits(1)) == 0 {
+            BlockStrategy::FixedBlocksize
+        } else {
+            BlockStrategy::VariableBlocksize
         };
 
+        let block_size_bits = try!(r.read_bits(4)) as u32;
+        let sample_rate_bits = try!(r.read_bits(4)) as u32;
+        let channel_val_bits = try!(r.read_bits(4)) as u32;
+        let sample_size_bits = try!(r.read_bits(3)) as u32;
+        if try!(r.read_bits(1)) != 0 {
+            return Err(SurfboardError::Malformed { offset: offset, what: "frame header reserved bit" });
+        }
+
         let channel_val = match channel_val_bits {
             0 => Channels::Mono,
             1 => Channels::LR,
@@ -325,7 +659,7 @@ impl FrameHeader {
             8 => Channels::LS,
             9 => Channels::SR,
             10 => Channels::MS,
-            _ => panic!("invalid channel setting!"),
+            _ => return Err(SurfboardError::Unsupported { offset: offset, what: "channel assignment", code: channel_val_bits }),
         };
 
         let sample_size = match sample_size_bits {
@@ -335,230 +669,1483 @@ impl FrameHeader {
             4 => 16,
             5 => 20,
             6 => 24,
-            _ => panic!("incorrect sample size!"),
+            _ => return Err(SurfboardError::Unsupported { offset: offset, what: "sample size code", code: sample_size_bits }),
         };
 
-        let mut frame_num = 0;
-        let mut sample_num = 0;
-        if block_strategy == BlockStrategy::FixedBlocksize {
-            frame_num = decode_utf8_val(r, &block_strategy);
-        } else {
-            sample_num = decode_utf8_val(r, &block_strategy);
-        }
+        let coded_number = try!(decode_utf8_val(r, &block_strategy, offset));
+        let (frame_number, first_sample_number) = match block_strategy {
+            BlockStrategy::FixedBlocksize => (Some(coded_number), None),
+            BlockStrategy::VariableBlocksize => (None, Some(coded_number)),
+        };
 
+        // Uncommon block sizes and sample rates are stored at the end of the
+        // header, block size first, both big endian.
         let block_size = match block_size_bits {
             1 => 192,
-            2 ... 5 => (576 * (1 << (block_size_bits - 2))),
-            6 => { (r.read_u8().unwrap() + 1) as u16 },
-            7 => { r.read_u16::<LittleEndian>().unwrap() + 1 },
-            8 ... 15 => (256 * (1 << (block_size_bits - 8))),
-            _ => panic!("incorrect block size!"),
+            2 ... 5 => 576 * (1 << (block_size_bits - 2)),
+            6 => try!(r.read_bits(8)) as u16 + 1,
+            7 => {
+                let t_size = try!(r.read_bits(16)) as u16;
+                if t_size == 0xFFFF {
+                    return Err(SurfboardError::Malformed { offset: offset, what: "frame block size" });
+                }
+                t_size + 1
+            },
+            8 ... 15 => 256 * (1 << (block_size_bits - 8)),
+            _ => return Err(SurfboardError::Unsupported { offset: offset, what: "block size code", code: block_size_bits }),
         };
 
-        let crc_8 = r.read_u8().unwrap();
+        let sample_rate = match sample_rate_bits {
+            0 => rate,
+            1 => 88200,
+            2 => 176400,
+            3 => 192000,
+            4 => 8000,
+            5 => 16000,
+            6 => 22050,
+            7 => 24000,
+            8 => 32000,
+            9 => 44100,
+            10 => 48000,
+            11 => 96000,
+            12 => try!(r.read_bits(8)) as u32 * 1000,
+            13 => try!(r.read_bits(16)) as u32,
+            14 => try!(r.read_bits(16)) as u32 * 10,
+            _ => return Err(SurfboardError::Unsupported { offset: offset, what: "sample rate code", code: sample_rate_bits }),
+        };
 
-        println!("\nsync code: {:0>8b}", sync_code);
-        println!("Frame number: {}", frame_num);
-        println!("Sample number: {}", sample_num);
-        println!("block strategy: {:?}", block_strategy);
-        println!("block size: {}", block_size);
-        println!("sample rate: {}", sample_rate);
-        println!("channel assignment: {:?}", channel_val);
-        println!("sample size: {}", sample_size);
-        println!("CRC-8: {}", crc_8);
+        let crc_8_computed = r.crc8();
+        let crc_8 = try!(r.read_bits(8)) as u8;
+        if verify && crc_8 != crc_8_computed {
+            return Err(SurfboardError::CrcMismatch {
+                offset: offset,
+                what: "frame header CRC-8",
+                expected: crc_8 as u32,
+                computed: crc_8_computed as u32,
+            });
+        }
 
-        FrameHeader {
+        Ok(FrameHeader {
             sync_code: sync_code,
             block_strategy: block_strategy,
+            frame_number: frame_number,
+            first_sample_number: first_sample_number,
             block_size: block_size,
             sample_rate: sample_rate,
             channel_val: channel_val,
             sample_size: sample_size,
             crc_8: crc_8,
-        }
+        })
     }
 }
 
 #[derive(Debug)]
-enum SubframeType {
+pub enum SubframeType {
     Constant,
     Verbatim,
     Fixed,
     LPC,
 }
 
-struct Subframe {
-    sub_type: SubframeType,
-    wasted_bits_per_sample: bool,
-    order: u32,
-    samples: Vec<i32>,
-}
-
-// Will require further thinking...
-fn signExtend(x: u64, n: u32) -> i32 {
-	if x & (1 << (n - 1)) != 0 {
-		// Sign extend x.
-		return (x | (!0 << n)) as i32
-	}
-	x as i32
+#[derive(Debug)]
+pub struct Subframe {
+    pub sub_type: SubframeType,
+    pub wasted_bits_per_sample: u32,
+    pub order: u32,
+    pub samples: Vec<i32>,
 }
 
 impl Subframe {
-    pub fn parse<R: io::Read + io::Seek>(r: &mut R, bps: u8, block_size: u16) -> Subframe {
-        let header = r.read_u8().unwrap();
-
-        println!("\nheader: {:0>8b}", header);
+    pub fn parse<R: io::Read>(r: &mut BitReader<R>, bps: u8, block_size: u16) -> Result<Subframe> {
+        let offset = r.position();
 
-        if (header >> 7) != 0 {
-            panic!("non-zero padding!");
+        if try!(r.read_bits(1)) != 0 {
+            return Err(SurfboardError::Malformed { offset: offset, what: "subframe padding bit" });
         }
 
-        let sub_type_bits = ((header as u32) << 1) >> 2;
+        let sub_type_bits = try!(r.read_bits(6)) as u32;
         let mut order = 0;
         let sub_type = match sub_type_bits {
             0 => SubframeType::Constant,
             1 => SubframeType::Verbatim,
             8 ... 12 => { order = sub_type_bits & 0x07; SubframeType::Fixed },
             32 ... 63 => { order = (sub_type_bits & 0x1F) + 1; SubframeType::LPC },
-            _ => panic!("Unknown subframe type!"),
+            _ => return Err(SurfboardError::Unsupported { offset: offset, what: "subframe type", code: sub_type_bits }),
         };
 
-        let wasted_bits_per_sample = match (header << 7) >> 7 {
-            0 => false,
-            1 => true,
-            _ => panic!("wut"),
-        };
-
-        println!("subframe type: {:?}", sub_type);
-        println!("order: {}", order);
-        println!("wasted_bits_per_sample: {}", wasted_bits_per_sample);
+        // Low bits that are zero in every sample are not stored; the count is
+        // unary coded after the flag and shifted back in after decoding.
+        let mut wasted_bits_per_sample = 0;
+        if try!(r.read_bits(1)) == 1 {
+            wasted_bits_per_sample = try!(r.read_unary()) + 1;
+            if wasted_bits_per_sample >= bps as u32 {
+                return Err(SurfboardError::Malformed { offset: offset, what: "subframe wasted bits" });
+            }
+        }
 
-        let samples = Subframe::decode_samples(r, &sub_type, order, block_size, bps);
+        let mut samples = try!(Subframe::decode_samples(r, &sub_type, order, block_size, bps - wasted_bits_per_sample as u8));
+        if wasted_bits_per_sample > 0 {
+            for sample in samples.iter_mut() {
+                *sample <<= wasted_bits_per_sample;
+            }
+        }
 
-        Subframe {
+        Ok(Subframe {
             sub_type: sub_type,
             wasted_bits_per_sample: wasted_bits_per_sample,
             order: order,
             samples: samples,
-        }
+        })
     }
 
-    fn decode_samples<R: io::Read + io::Seek>(r: &mut R, sub_type: &SubframeType, order: u32, block_size: u16, bps: u8) -> Vec<i32> {
-        let mut samples = Vec::with_capacity(block_size as usize);
-        println!("bits per sample: {}", bps);
-        let take_b = (((bps as f32) / 8.0) * order as f32) as u64;
-        println!("bits to read: {}", take_b * 8);
+    fn decode_samples<R: io::Read>(r: &mut BitReader<R>, sub_type: &SubframeType, order: u32, block_size: u16, bps: u8) -> Result<Vec<i32>> {
+        let offset = r.position();
+        let block_size = block_size as usize;
+        let order = order as usize;
+        let bps = bps as u32;
+        let mut samples = Vec::with_capacity(block_size);
+
+        match *sub_type {
+            SubframeType::Constant => {
+                let value = try!(r.read_signed(bps)) as i32;
+                samples.resize(block_size, value);
+                return Ok(samples);
+            },
+            SubframeType::Verbatim => {
+                for _ in 0..block_size {
+                    samples.push(try!(r.read_signed(bps)) as i32);
+                }
+                return Ok(samples);
+            },
+            _ => (),
+        }
+
+        if order > block_size {
+            return Err(SurfboardError::Malformed { offset: offset, what: "subframe predictor order" });
+        }
+
+        for _ in 0..order {
+            samples.push(try!(r.read_signed(bps)) as i32);
+        }
+
+        let mut coefs = Vec::with_capacity(order);
+        let mut shift = 0;
+        if let SubframeType::LPC = *sub_type {
+            let precision = try!(r.read_bits(4)) as u32 + 1;
+            if precision == 16 {
+                return Err(SurfboardError::Malformed { offset: offset, what: "LPC coefficient precision" });
+            }
+
+            let t_shift = try!(r.read_signed(5));
+            if t_shift < 0 {
+                return Err(SurfboardError::Unsupported { offset: offset, what: "negative LPC shift", code: t_shift as u32 });
+            }
+            shift = t_shift as u32;
 
-        let mut warm_up_data_v = Vec::new();
-        {
-            let mut data_handle = r.take(take_b);
-            data_handle.read_to_end(&mut warm_up_data_v).unwrap();
+            for _ in 0..order {
+                coefs.push(try!(r.read_signed(precision)));
+            }
+        } else {
+            coefs = match order {
+                0 => vec![],
+                1 => vec![1],
+                2 => vec![2, -1],
+                3 => vec![3, -3, 1],
+                _ => vec![4, -6, 4, -1],
+            };
         }
 
-        let mut warm_up_data = 0;
-        for (i, byte) in warm_up_data_v.iter().enumerate() {
-            warm_up_data += (*byte as u64) << (8 * i);
+        let residual = try!(Subframe::decode_residual(r, order, block_size));
+
+        for res in residual {
+            let n = samples.len();
+            let mut prediction: i64 = 0;
+            for (j, coef) in coefs.iter().enumerate() {
+                prediction += coef * samples[n - j - 1] as i64;
+            }
+            samples.push((res + (prediction >> shift)) as i32);
         }
-        let sample = signExtend(warm_up_data, bps as u32);
-        println!("warm up data: {}", warm_up_data);
 
-        samples.push(sample);
+        Ok(samples)
+    }
 
-        let t_header = r.read_u32::<LittleEndian>().unwrap();
-        let precision = ((t_header << 4) >> 4) + 1;
-        println!("header: {:0>32b}", t_header);
-        println!("precision: {}", precision);
+    /// Partitioned Rice coded residual, shared by FIXED and LPC subframes.
+    fn decode_residual<R: io::Read>(r: &mut BitReader<R>, order: usize, block_size: usize) -> Result<Vec<i64>> {
+        let offset = r.position();
+        let param_bits = match try!(r.read_bits(2)) {
+            0 => 4,
+            1 => 5,
+            method => return Err(SurfboardError::Unsupported { offset: offset, what: "residual coding method", code: method as u32 }),
+        };
+        let escape = (1 << param_bits) - 1;
 
-        samples
+        let partition_order = try!(r.read_bits(4)) as u32;
+        let partitions = 1usize << partition_order;
+        let partition_size = block_size >> partition_order;
+        if partition_size << partition_order != block_size || partition_size < order {
+            return Err(SurfboardError::Malformed { offset: offset, what: "residual partition order" });
+        }
+
+        let mut residual = Vec::with_capacity(block_size - order);
+        for partition in 0..partitions {
+            let count = if partition == 0 { partition_size - order } else { partition_size };
+            let param = try!(r.read_bits(param_bits)) as u32;
+
+            if param == escape {
+                let raw_bits = try!(r.read_bits(5)) as u32;
+                for _ in 0..count {
+                    residual.push(try!(r.read_signed(raw_bits)));
+                }
+            } else {
+                for _ in 0..count {
+                    residual.push(try!(r.read_rice(param)));
+                }
+            }
+        }
+
+        Ok(residual)
     }
 }
 
-struct Frame {
-    header: FrameHeader,
-    subframes: Vec<Subframe>,
-    footer: u16,
+#[derive(Debug)]
+pub struct Frame {
+    pub header: FrameHeader,
+    pub subframes: Vec<Subframe>,
+    pub footer: u16,
 }
 
 impl Frame {
-    pub fn parse<R: io::Read + io::Seek>(r: &mut R, sample_rate: u32, sample_size: u8) -> Frame {
-        let header = FrameHeader::parse(r, sample_rate, sample_size);
+    /// With `verify` set, a frame whose CRC-8 or CRC-16 does not match is an error.
+    pub fn parse<R: io::Read + io::Seek>(r: &mut R, sample_rate: u32, sample_size: u8, verify: bool) -> Result<Frame> {
+        let offset = try!(tell(r));
+        match Frame::decode(r, offset, sample_rate, sample_size, verify) {
+            // Damage inside a subframe usually breaks its structure before the
+            // CRC-16 at the end of the frame is reached
+            Err(e @ SurfboardError::Malformed { .. }) |
+            Err(e @ SurfboardError::Unsupported { .. }) if verify => {
+                try!(r.seek(io::SeekFrom::Start(offset)));
+                match FrameHeader::parse(&mut BitReader::new(r, offset), sample_rate, sample_size, true) {
+                    Ok(_) => Err(try!(check_frame_crc(r, offset, sample_rate, sample_size, e))),
+                    Err(_) => Err(e),
+                }
+            },
+            result => result,
+        }
+    }
+
+    fn decode<R: io::Read + io::Seek>(r: &mut R, offset: u64, sample_rate: u32, sample_size: u8, verify: bool) -> Result<Frame> {
+        let mut bits = BitReader::new(r, offset);
+        let header = try!(FrameHeader::parse(&mut bits, sample_rate, sample_size, verify));
 
         let bps = header.sample_size;
         let block_size = header.block_size;
 
         let mut subframes = Vec::new();
-        let mut subframe: Option<Subframe> = None;
         for channel in 0..Channels::channel_num(header.channel_val) {
             let mut t_bps = bps;
             match header.channel_val {
                 Channels::SR => { if channel == 0 { t_bps += 1; } },
-                Channels::LS => { if channel == 1 { t_bps += 1; } },
+                Channels::LS | Channels::MS => { if channel == 1 { t_bps += 1; } },
                 _ => (),
             }
-            subframe = Some(Subframe::parse(r, t_bps, block_size));
-            subframes.push(subframe.unwrap());
+            subframes.push(try!(Subframe::parse(&mut bits, t_bps, block_size)));
         }
 
-        let footer = r.read_u16::<LittleEndian>().unwrap();
-        println!("footer CRC-16: {}", footer);
+        bits.align();
+        let crc_16_computed = bits.crc16();
+        let footer = try!(bits.read_bits(16)) as u16;
+        if verify && footer != crc_16_computed {
+            return Err(SurfboardError::CrcMismatch {
+                offset: offset,
+                what: "frame CRC-16",
+                expected: footer as u32,
+                computed: crc_16_computed as u32,
+            });
+        }
 
-        Frame {
+        let mut frame = Frame {
             header: header,
             subframes: subframes,
             footer: footer,
+        };
+        frame.decorrelate();
+
+        Ok(frame)
+    }
+
+    /// Rebuilds left and right from the stereo modes that store a side
+    /// channel, so every subframe ends up holding a real channel.
+    fn decorrelate(&mut self) {
+        if self.subframes.len() != 2 {
+            return;
+        }
+        let (first, second) = self.subframes.split_at_mut(1);
+        let a = &mut first[0].samples;
+        let b = &mut second[0].samples;
+
+        match self.header.channel_val {
+            Channels::LS => {
+                for (left, side) in a.iter().zip(b.iter_mut()) {
+                    *side = *left - *side;
+                }
+            },
+            Channels::SR => {
+                for (side, right) in a.iter_mut().zip(b.iter()) {
+                    *side = *side + *right;
+                }
+            },
+            Channels::MS => {
+                for (mid, side) in a.iter_mut().zip(b.iter_mut()) {
+                    let m = ((*mid as i64) << 1) | (*side as i64 & 1);
+                    let s = *side as i64;
+                    *mid = ((m + s) >> 1) as i32;
+                    *side = ((m - s) >> 1) as i32;
+                }
+            },
+            _ => (),
         }
     }
 }
 
+/// Checks the CRC-16 of the frame at `offset`, taking the next frame header
+/// as its end. A mismatch replaces `err`, since the frame was damaged rather
+/// than badly encoded.
+fn check_frame_crc<R: io::Read + io::Seek>(r: &mut R, offset: u64, sample_rate: u32, sample_size: u8, err: SurfboardError) -> Result<SurfboardError> {
+    try!(resync(r, offset, sample_rate, sample_size));
+    let end = try!(tell(r));
+    try!(r.seek(io::SeekFrom::Start(offset)));
+    let bytes = try!(read_bytes(r, (end - offset) as u32, "FLAC frame", offset));
+    if bytes.len() < 2 {
+        return Ok(err);
+    }
+
+    let (data, footer) = bytes.split_at(bytes.len() - 2);
+    let expected = (footer[0] as u16) << 8 | footer[1] as u16;
+    let computed = crc16(data);
+    if expected == computed {
+        return Ok(err);
+    }
+    Ok(SurfboardError::CrcMismatch {
+        offset: offset,
+        what: "frame CRC-16",
+        expected: expected as u32,
+        computed: computed as u32,
+    })
+}
+
+/// Moves to the next frame after the one at `offset`, passing over sync codes
+/// in frame data whose header does not check out, or to the end of the stream.
+fn resync<R: io::Read + io::Seek>(r: &mut R, offset: u64, sample_rate: u32, sample_size: u8) -> Result<()> {
+    let mut pos = offset + 1;
+    loop {
+        try!(r.seek(io::SeekFrom::Start(pos)));
+        try!(find_sync(r));
+        pos = try!(tell(r));
+        if try!(at_eof(r)) {
+            return Ok(());
+        }
+
+        let header = FrameHeader::parse(&mut BitReader::new(r, pos), sample_rate, sample_size, true);
+        match header {
+            Ok(_) => {
+                try!(r.seek(io::SeekFrom::Start(pos)));
+                return Ok(());
+            },
+            Err(SurfboardError::Io(e)) => return Err(SurfboardError::Io(e)),
+            Err(_) => pos += 1,
+        }
+    }
+}
+
+/// What to do when a frame's CRC does not match its contents.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrcCheck {
+    /// Stop decoding and return the mismatch.
+    Fail,
+    /// Keep the frame if it still decodes, drop it if not, and record the
+    /// mismatch in `Flac::errors`.
+    Warn,
+    /// Drop the frame, record the mismatch in `Flac::errors` and carry on
+    /// from the next frame sync code.
+    Skip,
+}
+
+#[derive(Debug)]
 pub struct Flac {
-    stream_info: Block,
-    blocks: Option<Vec<Block>>,
-    frames: Vec<Frame>,
+    pub stream_info: Block,
+    pub blocks: Option<Vec<Block>>,
+    pub frames: Vec<Frame>,
+    /// Problems tolerated while decoding under `CrcCheck::Warn` or `CrcCheck::Skip`.
+    pub errors: Vec<SurfboardError>,
 }
 
 impl Flac {
-    pub fn parse<R: io::Read + io::Seek>(r: &mut R) -> Flac {
-        let stream_info = Block::parse(r);
-        let mut block_list = Vec::new();
-
-        let mut blocks = None;
-
-        if stream_info.last_meta == false {
-            let mut t_block = Block::parse(r);
-            block_list.push(t_block.clone());
-
-            while t_block.last_meta == false {
-                block_list.push(t_block);
-                t_block = Block::parse(r);
-            }
-
-            blocks = Some(block_list);
-        }
-
-        // There must be a better way to do this!
-        let (sample_rate, size) = match stream_info.clone().type_data.unwrap() {
-            BlockType::StreamInfo {
-                min_block_size,
-                max_block_size,
-                min_frame_size,
-                max_frame_size,
-                sample_rate,
-                num_channels,
-                bits_per_sample,
-                total_samples,
-                sig,
-            } => (sample_rate, bits_per_sample),
-            _ => panic!("Not StreamInfo?"),
+    /// Parses a FLAC stream, starting from its `fLaC` marker. Any CRC
+    /// mismatch is an error.
+    pub fn parse<R: io::Read + io::Seek>(r: &mut R) -> Result<Flac> {
+        Flac::parse_with(r, CrcCheck::Fail)
+    }
+
+    /// Parses a FLAC stream, handling CRC mismatches as `check` says.
+    pub fn parse_with<R: io::Read + io::Seek>(r: &mut R, check: CrcCheck) -> Result<Flac> {
+        let offset = try!(tell(r));
+        let (stream_info, blocks) = try!(read_metadata(r));
+
+        let (sample_rate, size, total_samples) = match stream_info.type_data {
+            Some(BlockType::StreamInfo { sample_rate, bits_per_sample, total_samples, .. }) =>
+                (sample_rate, bits_per_sample, total_samples),
+            _ => return Err(SurfboardError::Malformed { offset: offset + 4, what: "first metadata block, expected STREAMINFO" }),
         };
 
+        // A total of 0 means the encoder did not know it, so read to the end
         let mut frames = Vec::new();
-        let frame = Frame::parse(r, sample_rate, size);
-        frames.push(frame);
+        let mut errors = Vec::new();
+        let mut decoded = 0;
+        let mut dropped = false;
+        while (total_samples == 0 || decoded < total_samples) && !try!(at_eof(r)) {
+            let frame_start = try!(tell(r));
+            let frame = match Frame::parse(r, sample_rate, size, true) {
+                Ok(frame) => frame,
+                Err(e @ SurfboardError::CrcMismatch { .. }) => match check {
+                    CrcCheck::Fail => return Err(e),
+                    CrcCheck::Warn => {
+                        errors.push(e);
+                        try!(r.seek(io::SeekFrom::Start(frame_start)));
+                        match Frame::parse(r, sample_rate, size, false) {
+                            Ok(frame) => frame,
+                            Err(SurfboardError::Io(e)) => return Err(SurfboardError::Io(e)),
+                            // Too damaged to decode at all, so drop it
+                            Err(_) => {
+                                try!(resync(r, frame_start, sample_rate, size));
+                                dropped = true;
+                                continue;
+                            },
+                        }
+                    },
+                    CrcCheck::Skip => {
+                        errors.push(e);
+                        try!(resync(r, frame_start, sample_rate, size));
+                        dropped = true;
+                        continue;
+                    },
+                },
+                // Corruption that breaks the frame structure is dropped the same way
+                Err(e @ SurfboardError::Malformed { .. }) |
+                Err(e @ SurfboardError::Unsupported { .. }) if check != CrcCheck::Fail => {
+                    errors.push(e);
+                    try!(resync(r, frame_start, sample_rate, size));
+                    dropped = true;
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            decoded += frame.header.block_size as u64;
+            frames.push(frame);
+        }
+
+        // Dropped frames leave the stream short by design
+        if decoded < total_samples && !dropped {
+            return Err(SurfboardError::Truncated { offset: try!(tell(r)), what: "FLAC stream" });
+        }
 
-        Flac {
+        Ok(Flac {
             stream_info: stream_info,
             blocks: blocks,
             frames: frames,
+            errors: errors,
+        })
+    }
+
+    /// The decoded audio, one vector of samples per channel.
+    pub fn samples(&self) -> Vec<Vec<i32>> {
+        let num_channels = self.frames.first().map_or(0, |f| f.subframes.len());
+        let mut channels = vec![Vec::new(); num_channels];
+
+        for frame in self.frames.iter() {
+            for (channel, subframe) in channels.iter_mut().zip(frame.subframes.iter()) {
+                channel.extend(subframe.samples.iter().cloned());
+            }
+        }
+
+        if let Some(BlockType::StreamInfo { total_samples, .. }) = self.stream_info.type_data {
+            if total_samples != 0 {
+                for channel in channels.iter_mut() {
+                    channel.truncate(total_samples as usize);
+                }
+            }
+        }
+
+        channels
+    }
+
+    /// Hashes the decoded audio the way the reference encoder does and
+    /// compares it with the STREAMINFO signature.
+    pub fn verify(&self) -> Md5Status {
+        let (bits_per_sample, expected) = match self.stream_info.type_data {
+            Some(BlockType::StreamInfo { bits_per_sample, ref sig, .. }) => (bits_per_sample, sig_bytes(sig)),
+            _ => return Md5Status::NotSet,
+        };
+
+        if expected == [0; 16] {
+            return Md5Status::NotSet;
+        }
+
+        // Interleaved samples, little endian, in the fewest whole bytes
+        let bytes_per_sample = (bits_per_sample as usize + 7) / 8;
+        let channels = self.samples();
+        let length = channels.first().map_or(0, |c| c.len());
+        let mut md5 = Md5::new();
+        let mut buf = Vec::with_capacity(channels.len() * bytes_per_sample);
+        for i in 0..length {
+            buf.clear();
+            for channel in channels.iter() {
+                for b in 0..bytes_per_sample {
+                    buf.push((channel[i] >> (8 * b)) as u8);
+                }
+            }
+            md5.update(&buf);
+        }
+        let computed = md5.finish();
+
+        if computed == expected {
+            Md5Status::Match
+        } else {
+            Md5Status::Mismatch { expected: expected, computed: computed }
+        }
+    }
+}
+
+/// Reads the `fLaC` marker and every metadata block after it.
+fn read_metadata<R: io::Read + io::Seek>(r: &mut R) -> Result<(Block, Option<Vec<Block>>)> {
+    let offset = try!(tell(r));
+    try!(expect_id(r, "fLaC", offset));
+
+    let stream_info = try!(Block::parse(r));
+    let mut block_list = Vec::new();
+
+    let mut blocks = None;
+
+    if stream_info.last_meta == false {
+        let mut last_meta = false;
+        while !last_meta {
+            let t_block = try!(Block::parse(r));
+            last_meta = t_block.last_meta;
+            block_list.push(t_block);
+        }
+
+        blocks = Some(block_list);
+    }
+
+    Ok((stream_info, blocks))
+}
+
+/// Below this many bytes, `FlacReader::seek_to_sample` stops bisecting and
+/// decodes forward.
+const SEEK_LINEAR_BYTES: u64 = 1 << 16;
+
+/// Decodes a FLAC stream a frame at a time, with sample accurate seeking.
+pub struct FlacReader<R> {
+    r: R,
+    pub stream_info: Block,
+    pub blocks: Option<Vec<Block>>,
+    audio_offset: u64,
+    sample_rate: u32,
+    sample_size: u8,
+    block_size: u16,
+    total_samples: u64,
+    next_sample: u64,
+    /// Samples to drop from the next frame after a seek.
+    skip: u64,
+}
+
+impl<R: io::Read + io::Seek> FlacReader<R> {
+    /// Reads the metadata blocks and stops at the first frame.
+    pub fn new(mut r: R) -> Result<FlacReader<R>> {
+        let offset = try!(tell(&mut r));
+        let (stream_info, blocks) = try!(read_metadata(&mut r));
+
+        let (sample_rate, sample_size, block_size, total_samples) = match stream_info.type_data {
+            Some(BlockType::StreamInfo { sample_rate, bits_per_sample, max_block_size, total_samples, .. }) =>
+                (sample_rate, bits_per_sample, max_block_size, total_samples),
+            _ => return Err(SurfboardError::Malformed { offset: offset + 4, what: "first metadata block, expected STREAMINFO" }),
+        };
+        let audio_offset = try!(tell(&mut r));
+
+        Ok(FlacReader {
+            r: r,
+            stream_info: stream_info,
+            blocks: blocks,
+            audio_offset: audio_offset,
+            sample_rate: sample_rate,
+            sample_size: sample_size,
+            block_size: block_size,
+            total_samples: total_samples,
+            next_sample: 0,
+            skip: 0,
+        })
+    }
+
+    /// Decodes the next frame, one vector of samples per channel, or `None`
+    /// at the end of the stream.
+    pub fn next_block(&mut self) -> Result<Option<Vec<Vec<i32>>>> {
+        if (self.total_samples != 0 && self.next_sample >= self.total_samples) || try!(at_eof(&mut self.r)) {
+            return Ok(None);
+        }
+
+        let frame = try!(Frame::parse(&mut self.r, self.sample_rate, self.sample_size, true));
+        let skip = self.skip as usize;
+        self.skip = 0;
+
+        let mut channels: Vec<Vec<i32>> = frame.subframes.into_iter().map(|s| s.samples.into_iter().skip(skip).collect()).collect();
+        if self.total_samples != 0 {
+            let left = (self.total_samples - self.next_sample) as usize;
+            for channel in channels.iter_mut() {
+                channel.truncate(left);
+            }
+        }
+        self.next_sample += channels.first().map_or(0, |c| c.len()) as u64;
+
+        Ok(Some(channels))
+    }
+
+    /// Positions the reader so that the next block starts exactly at sample
+    /// `n`. The seek table narrows the search, then the frames in between
+    /// are bisected and the last few decoded.
+    pub fn seek_to_sample(&mut self, n: u64) -> Result<()> {
+        if self.total_samples != 0 && n >= self.total_samples {
+            return Err(SurfboardError::SeekPastEnd { sample: n, total: self.total_samples });
+        }
+
+        let mut lo = self.audio_offset;
+        let mut hi = try!(self.r.seek(io::SeekFrom::End(0)));
+        let mut lo_sample = 0;
+
+        if let Some(ref blocks) = self.blocks {
+            for block in blocks.iter() {
+                if let Some(BlockType::Seektable(ref points)) = block.type_data {
+                    for point in points.iter().filter(|p| !p.is_placeholder()) {
+                        let pos = self.audio_offset + point.stream_offset;
+                        if point.sample_number <= n && point.sample_number >= lo_sample && pos < hi {
+                            lo = pos;
+                            lo_sample = point.sample_number;
+                        } else if point.sample_number > n && pos > lo && pos < hi {
+                            hi = pos;
+                        }
+                    }
+                }
+            }
+        }
+
+        while hi - lo > SEEK_LINEAR_BYTES {
+            let mid = lo + (hi - lo) / 2;
+            match try!(self.probe_frame(mid, hi)) {
+                Some((pos, first)) if first <= n => lo = pos,
+                Some((pos, _)) => hi = pos,
+                None => hi = mid,
+            }
+        }
+
+        try!(self.r.seek(io::SeekFrom::Start(lo)));
+        loop {
+            let pos = try!(tell(&mut self.r));
+            if try!(at_eof(&mut self.r)) {
+                return Err(SurfboardError::Truncated { offset: pos, what: "FLAC stream" });
+            }
+            let header = try!(Frame::parse(&mut self.r, self.sample_rate, self.sample_size, true)).header;
+            let first = header.first_sample(self.block_size);
+            if n < first + header.block_size as u64 {
+                try!(self.r.seek(io::SeekFrom::Start(pos)));
+                self.next_sample = n;
+                self.skip = n - first;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Finds the first frame header at or after `from` and before `limit`,
+    /// returning its position and first sample number.
+    fn probe_frame(&mut self, from: u64, limit: u64) -> Result<Option<(u64, u64)>> {
+        try!(self.r.seek(io::SeekFrom::Start(from)));
+        loop {
+            try!(find_sync(&mut self.r));
+            let pos = try!(tell(&mut self.r));
+            if pos >= limit {
+                return Ok(None);
+            }
+
+            // A sync code inside frame data can carry a valid CRC-8, but the
+            // whole frame has to decode and pass its CRC-16 as well
+            match Frame::parse(&mut self.r, self.sample_rate, self.sample_size, true) {
+                Ok(frame) => return Ok(Some((pos, frame.header.first_sample(self.block_size)))),
+                Err(SurfboardError::Io(e)) => return Err(SurfboardError::Io(e)),
+                Err(_) => try!(self.r.seek(io::SeekFrom::Start(pos + 1))),
+            };
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+
+/// Outcome of checking the STREAMINFO MD5 signature.
+#[derive(Debug, PartialEq)]
+pub enum Md5Status {
+    Match,
+    Mismatch { expected: [u8; 16], computed: [u8; 16] },
+    /// The encoder left the signature as all zeros.
+    NotSet,
+}
+
+/// Moves `r` to the next frame sync code (`0xFFF8` or `0xFFF9`), or to the
+/// end of the stream if there is none.
+fn find_sync<R: io::Read + io::Seek>(r: &mut R) -> Result<()> {
+    let mut last = 0;
+    loop {
+        let mut byte = [0; 1];
+        if try!(r.read(&mut byte)) == 0 {
+            return Ok(());
+        }
+        if last == 0xFF && byte[0] & 0xFE == 0xF8 {
+            try!(r.seek(io::SeekFrom::Current(-2)));
+            return Ok(());
+        }
+        last = byte[0];
+    }
+}
+
+/// Checks for end of stream without consuming anything.
+fn at_eof<R: io::Read + io::Seek>(r: &mut R) -> Result<bool> {
+    let mut byte = [0; 1];
+    if try!(r.read(&mut byte)) == 0 {
+        return Ok(true);
+    }
+    try!(r.seek(io::SeekFrom::Current(-1)));
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+    use std::io::{self, Cursor, Seek};
+    use bitreader::BitReader;
+    use bitwriter::BitWriter;
+    use byteorder::{BigEndian, WriteBytesExt};
+    use crc::{crc8, crc16};
+    use error::{Result, SurfboardError};
+    use md5::Md5;
+    use super::{Block, BlockType, CrcCheck, CueIndex, Flac, FlacReader, Frame, FrameHeader, Md5Status, SeekPoint, Subframe, SubframeType};
+
+    /// A 16-bit stereo stream of `length` samples per channel in frames of
+    /// 4096 VERBATIM samples, with a SEEKTABLE if there are `seek_points`,
+    /// and its samples.
+    fn encode(length: usize, seek_points: &[SeekPoint]) -> (Vec<u8>, Vec<Vec<i32>>) {
+        let mut channels = vec![Vec::new(), Vec::new()];
+        let mut md5 = Md5::new();
+        for i in 0..length {
+            let left = ((i as f64 * 0.03).sin() * 12000.0) as i32 + (i % 7) as i32;
+            let right = ((i as f64 * 0.011).cos() * 9000.0) as i32 - (i % 5) as i32;
+            md5.update(&[left as u8, (left >> 8) as u8, right as u8, (right >> 8) as u8]);
+            channels[0].push(left);
+            channels[1].push(right);
+        }
+
+        let mut bits = BitWriter::new();
+        bits.write_bits(0x664C6143, 32);
+        bits.write_bits(if seek_points.is_empty() { 0x80 } else { 0 }, 8);
+        bits.write_bits(34, 24);
+        bits.write_bits(4096, 16);
+        bits.write_bits(4096, 16);
+        bits.write_bits(0, 24);
+        bits.write_bits(0, 24);
+        bits.write_bits(44100, 20);
+        bits.write_bits(1, 3);
+        bits.write_bits(15, 5);
+        bits.write_bits(length as u64 >> 32, 4);
+        bits.write_bits(length as u64 & 0xFFFFFFFF, 32);
+        for &b in md5.finish().iter() {
+            bits.write_bits(b as u64, 8);
+        }
+        if !seek_points.is_empty() {
+            bits.write_bits(0x83, 8);
+            bits.write_bits(18 * seek_points.len() as u64, 24);
+            for point in seek_points.iter() {
+                bits.write_bits(point.sample_number >> 32, 32);
+                bits.write_bits(point.sample_number & 0xFFFFFFFF, 32);
+                bits.write_bits(point.stream_offset >> 32, 32);
+                bits.write_bits(point.stream_offset & 0xFFFFFFFF, 32);
+                bits.write_bits(point.frame_samples as u64, 16);
+            }
+        }
+        let mut bytes = bits.into_bytes();
+
+        let mut start = 0;
+        while start < length {
+            let end = cmp::min(start + 4096, length);
+            // 44.1 kHz independent 16-bit stereo, with the block size after
+            // the single byte frame number
+            let mut header = BitWriter::new();
+            header.write_bits(0xFFF8, 16);
+            header.write_bits(7, 4);
+            header.write_bits(9, 4);
+            header.write_bits(1, 4);
+            header.write_bits(4, 3);
+            header.write_bits(0, 1);
+            header.write_bits((start / 4096) as u64, 8);
+            header.write_bits((end - start - 1) as u64, 16);
+            let mut frame = header.into_bytes();
+            let crc = crc8(&frame);
+            frame.push(crc);
+
+            for channel in channels.iter() {
+                frame.push(0x02);
+                for &sample in channel[start..end].iter() {
+                    frame.push((sample >> 8) as u8);
+                    frame.push(sample as u8);
+                }
+            }
+            let crc = crc16(&frame);
+            frame.push((crc >> 8) as u8);
+            frame.push(crc as u8);
+
+            bytes.extend(frame);
+            start = end;
+        }
+        (bytes, channels)
+    }
+
+    /// A subframe header: padding bit, type and unary coded wasted bits.
+    fn header(sub_type: u64, wasted: u32) -> BitWriter {
+        let mut bits = BitWriter::new();
+        bits.write_bits(0, 1);
+        bits.write_bits(sub_type, 6);
+        if wasted == 0 {
+            bits.write_bits(0, 1);
+        } else {
+            bits.write_bits(1, 1);
+            bits.write_unary(wasted - 1);
+        }
+        bits
+    }
+
+    fn decode(mut bits: BitWriter, bps: u8, block_size: u16) -> Subframe {
+        bits.align();
+        let data = bits.into_bytes();
+        let mut r = &data[..];
+        Subframe::parse(&mut BitReader::new(&mut r, 0), bps, block_size).unwrap()
+    }
+
+    #[test]
+    fn decodes_constant_subframes() {
+        let mut bits = header(0, 0);
+        bits.write_signed(-7, 8);
+        let subframe = decode(bits, 8, 4);
+        match subframe.sub_type { SubframeType::Constant => {}, ref t => panic!("{:?}", t) }
+        assert_eq!(subframe.samples, vec![-7; 4]);
+    }
+
+    #[test]
+    fn decodes_verbatim_subframes() {
+        let mut bits = header(1, 0);
+        for &sample in [1, -2, 127, -128].iter() {
+            bits.write_signed(sample, 8);
+        }
+        let subframe = decode(bits, 8, 4);
+        match subframe.sub_type { SubframeType::Verbatim => {}, ref t => panic!("{:?}", t) }
+        assert_eq!(subframe.samples, vec![1, -2, 127, -128]);
+    }
+
+    #[test]
+    fn decodes_fixed_subframes() {
+        // Order 2 predicts 2 * s[n - 1] - s[n - 2]
+        let mut bits = header(8 + 2, 0);
+        bits.write_signed(10, 16);
+        bits.write_signed(20, 16);
+        bits.write_bits(0, 2);
+        bits.write_bits(0, 4);
+        bits.write_bits(2, 4);
+        for &residual in [0, 1, -1].iter() {
+            bits.write_rice(residual, 2);
+        }
+        let subframe = decode(bits, 16, 5);
+        match subframe.sub_type { SubframeType::Fixed => {}, ref t => panic!("{:?}", t) }
+        assert_eq!(subframe.order, 2);
+        assert_eq!(subframe.samples, vec![10, 20, 30, 41, 51]);
+    }
+
+    #[test]
+    fn decodes_lpc_subframes() {
+        // Order 2 with coefficients 3 and -1 at 4 bits, shifted right by 1
+        let mut bits = header(32 + 1, 0);
+        bits.write_signed(100, 16);
+        bits.write_signed(90, 16);
+        bits.write_bits(3, 4);
+        bits.write_signed(1, 5);
+        bits.write_signed(3, 4);
+        bits.write_signed(-1, 4);
+        // Two partitions, the second escaped to raw 5-bit residuals
+        bits.write_bits(0, 2);
+        bits.write_bits(1, 4);
+        bits.write_bits(1, 4);
+        bits.write_rice(2, 1);
+        bits.write_bits(15, 4);
+        bits.write_bits(5, 5);
+        for &residual in [-3, 0, 4].iter() {
+            bits.write_signed(residual, 5);
+        }
+        let subframe = decode(bits, 16, 6);
+        match subframe.sub_type { SubframeType::LPC => {}, ref t => panic!("{:?}", t) }
+        assert_eq!(subframe.order, 2);
+        assert_eq!(subframe.samples, vec![100, 90, 87, 82, 79, 81]);
+    }
+
+    #[test]
+    fn shifts_wasted_bits_back_in() {
+        // CONSTANT at 8 bits with 2 wasted stores 6
+        let mut bits = header(0, 2);
+        bits.write_signed(-7, 6);
+        let subframe = decode(bits, 8, 3);
+        assert_eq!(subframe.wasted_bits_per_sample, 2);
+        assert_eq!(subframe.samples, vec![-28; 3]);
+
+        // FIXED at 16 bits with 3 wasted stores 13 bit warm-up samples
+        let mut bits = header(8 + 2, 3);
+        bits.write_signed(10, 13);
+        bits.write_signed(-20, 13);
+        bits.write_bits(0, 2);
+        bits.write_bits(0, 4);
+        bits.write_bits(2, 4);
+        for &residual in [0, 1, -1].iter() {
+            bits.write_rice(residual, 2);
+        }
+        let subframe = decode(bits, 16, 5);
+        assert_eq!(subframe.wasted_bits_per_sample, 3);
+        assert_eq!(subframe.samples, vec![80, -160, -400, -632, -872]);
+
+        // LPC at 16 bits with 4 wasted, the signal of `decodes_lpc_subframes`
+        // with every residual escaped
+        let mut bits = header(32 + 1, 4);
+        bits.write_signed(100, 12);
+        bits.write_signed(90, 12);
+        bits.write_bits(3, 4);
+        bits.write_signed(1, 5);
+        bits.write_signed(3, 4);
+        bits.write_signed(-1, 4);
+        bits.write_bits(0, 2);
+        bits.write_bits(0, 4);
+        bits.write_bits(15, 4);
+        bits.write_bits(5, 5);
+        for &residual in [2, -3, 0, 4].iter() {
+            bits.write_signed(residual, 5);
+        }
+        let subframe = decode(bits, 16, 6);
+        assert_eq!(subframe.wasted_bits_per_sample, 4);
+        assert_eq!(subframe.samples, [100, 90, 87, 82, 79, 81].iter().map(|&s| s << 4).collect::<Vec<i32>>());
+    }
+
+    #[test]
+    fn rejects_wasting_every_bit() {
+        let mut bits = header(0, 8);
+        bits.align();
+        let data = bits.into_bytes();
+        let mut r = &data[..];
+        match Subframe::parse(&mut BitReader::new(&mut r, 0), 8, 4) {
+            Err(SurfboardError::Malformed { what, .. }) => assert_eq!(what, "subframe wasted bits"),
+            result => panic!("{:?}", result),
+        }
+    }
+
+    /// The first 32 bits of a frame header, up to the coded number.
+    fn frame_header(variable: bool, block_size: u64, sample_rate: u64, channels: u64, sample_size: u64) -> BitWriter {
+        let mut bits = BitWriter::new();
+        bits.write_bits(0xFFF8 | variable as u64, 16);
+        bits.write_bits(block_size, 4);
+        bits.write_bits(sample_rate, 4);
+        bits.write_bits(channels, 4);
+        bits.write_bits(sample_size, 3);
+        bits.write_bits(0, 1);
+        bits
+    }
+
+    /// A whole frame: `header` and its CRC-8, then `subframes` and the CRC-16.
+    fn frame(header: BitWriter, mut subframes: BitWriter) -> Vec<u8> {
+        let mut bytes = header.into_bytes();
+        let crc = crc8(&bytes);
+        bytes.push(crc);
+        subframes.align();
+        bytes.extend(subframes.into_bytes());
+        let crc = crc16(&bytes);
+        bytes.push((crc >> 8) as u8);
+        bytes.push(crc as u8);
+        bytes
+    }
+
+    #[test]
+    fn decorrelates_stereo_frames() {
+        // The extremes give a side channel that needs the extra bit
+        let left = [1000, -2000, 32767, -32768, 7];
+        let right = [-1000, 2001, -32768, 32767, 7];
+        let side: Vec<i64> = left.iter().zip(right.iter()).map(|(&l, &r)| l - r).collect();
+        let mid: Vec<i64> = left.iter().zip(right.iter()).map(|(&l, &r)| (l + r) >> 1).collect();
+
+        for &(mode, first, first_bps, second, second_bps) in [
+            (8, &left[..], 16, &side[..], 17),
+            (9, &side[..], 17, &right[..], 16),
+            (10, &mid[..], 16, &side[..], 17),
+        ].iter() {
+            let mut header = frame_header(false, 6, 9, mode, 4);
+            header.write_bits(0, 8);
+            header.write_bits(left.len() as u64 - 1, 8);
+
+            let mut subframes = BitWriter::new();
+            for &(samples, bps) in [(first, first_bps), (second, second_bps)].iter() {
+                subframes.write_bits(0x02, 8);
+                for &sample in samples.iter() {
+                    subframes.write_signed(sample, bps);
+                }
+            }
+
+            let bytes = frame(header, subframes);
+            let frame = Frame::parse(&mut Cursor::new(bytes), 44100, 16, true).unwrap();
+            assert_eq!(frame.subframes[0].samples, left.iter().map(|&s| s as i32).collect::<Vec<_>>());
+            assert_eq!(frame.subframes[1].samples, right.iter().map(|&s| s as i32).collect::<Vec<_>>());
+        }
+    }
+
+    /// Parses `header` after adding its CRC-8.
+    fn parse_header(header: BitWriter) -> Result<FrameHeader> {
+        let mut bytes = header.into_bytes();
+        let crc = crc8(&bytes);
+        bytes.push(crc);
+        let mut r = &bytes[..];
+        FrameHeader::parse(&mut BitReader::new(&mut r, 0), 44100, 16, true)
+    }
+
+    /// `value` in the extended UTF-8 coding of frame and sample numbers.
+    fn utf8(value: u64) -> Vec<u8> {
+        if value < 0x80 {
+            return vec![value as u8];
+        }
+        let mut length = 2;
+        while value >> (5 * length + 1) != 0 {
+            length += 1;
+        }
+        let mut bytes = vec![(0xFF00 >> length) as u8 | (value >> (6 * (length - 1))) as u8];
+        for i in (0..length - 1).rev() {
+            bytes.push(0x80 | (value >> (6 * i)) as u8 & 0x3F);
+        }
+        bytes
+    }
+
+    #[test]
+    fn decodes_coded_numbers_of_every_length() {
+        for &(variable, value, length) in [
+            (false, 0x7F, 1),
+            (false, 0x80, 2),
+            (false, 0x7FF, 2),
+            (false, 0xFFFF, 3),
+            (false, 0x1FFFFF, 4),
+            (true, 0x3FFFFFF, 5),
+            (false, 0x7FFFFFFF, 6),
+            (true, 0xFFFFFFFFF, 7),
+        ].iter() {
+            let coded = utf8(value);
+            assert_eq!(coded.len(), length);
+            let mut header = frame_header(variable, 8, 9, 1, 4);
+            for &b in coded.iter() {
+                header.write_bits(b as u64, 8);
+            }
+
+            let header = parse_header(header).unwrap();
+            if variable {
+                assert_eq!((header.frame_number, header.first_sample_number), (None, Some(value)));
+            } else {
+                assert_eq!((header.frame_number, header.first_sample_number), (Some(value), None));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_coded_numbers() {
+        for &(variable, ref coded) in [
+            // A continuation byte first, then a bad continuation byte
+            (false, vec![0x80]),
+            (false, vec![0xC2, 0x41]),
+            // Seven bytes only code sample numbers, and eight never occur
+            (false, vec![0xFE, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80]),
+            (true, vec![0xFF, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80]),
+        ].iter() {
+            let mut header = frame_header(variable, 8, 9, 1, 4);
+            for &b in coded.iter() {
+                header.write_bits(b as u64, 8);
+            }
+            match parse_header(header) {
+                Err(SurfboardError::Malformed { what, .. }) => assert_eq!(what, "coded frame number"),
+                result => panic!("{:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_uncommon_block_sizes_and_rates_from_the_header_end() {
+        // Block size code, stored size, rate code, stored rate, then the
+        // expected block size and rate; a rate code of 0 takes the stream's
+        for &(size_code, stored_size, rate_code, stored_rate, block_size, sample_rate) in [
+            (6, (255, 8), 12, (37, 8), 256, 37000),
+            (7, (4607, 16), 13, (11025, 16), 4608, 11025),
+            (6, (0, 8), 14, (3780, 16), 1, 37800),
+            (7, (0xFFFE, 16), 0, (0, 0), 0xFFFF, 44100),
+            (8, (0, 0), 12, (192, 8), 256, 192000),
+        ].iter() {
+            let mut header = frame_header(false, size_code, rate_code, 1, 4);
+            header.write_bits(0, 8);
+            header.write_bits(stored_size.0, stored_size.1);
+            header.write_bits(stored_rate.0, stored_rate.1);
+
+            let header = parse_header(header).unwrap();
+            assert_eq!((header.block_size, header.sample_rate), (block_size, sample_rate));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_block_sizes_and_rates() {
+        let mut header = frame_header(false, 7, 9, 1, 4);
+        header.write_bits(0, 8);
+        header.write_bits(0xFFFF, 16);
+        match parse_header(header) {
+            Err(SurfboardError::Malformed { what, .. }) => assert_eq!(what, "frame block size"),
+            result => panic!("{:?}", result),
+        }
+
+        for &(size_code, rate_code, what, code) in [(0, 9, "block size code", 0), (8, 15, "sample rate code", 15)].iter() {
+            let mut header = frame_header(false, size_code, rate_code, 1, 4);
+            header.write_bits(0, 8);
+            match parse_header(header) {
+                Err(SurfboardError::Unsupported { what: w, code: c, .. }) => assert_eq!((w, c), (what, code)),
+                result => panic!("{:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn verifies_the_md5_signature() {
+        let (bytes, channels) = encode(10000, &[]);
+        let flac = Flac::parse(&mut Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(flac.samples(), channels);
+        assert_eq!(flac.verify(), Md5Status::Match);
+
+        // The signature follows the marker, block header and 18 bytes of STREAMINFO
+        let mut corrupt = bytes.clone();
+        corrupt[26] ^= 0xFF;
+        match Flac::parse(&mut Cursor::new(corrupt)).unwrap().verify() {
+            Md5Status::Mismatch { expected, computed } => {
+                assert_eq!(expected[0], bytes[26] ^ 0xFF);
+                assert_eq!(computed[0], bytes[26]);
+                assert_eq!(&computed[1..], &bytes[27..42]);
+            },
+            status => panic!("{:?}", status),
+        }
+
+        let mut unset = bytes.clone();
+        for b in unset[26..42].iter_mut() {
+            *b = 0;
+        }
+        assert_eq!(Flac::parse(&mut Cursor::new(unset)).unwrap().verify(), Md5Status::NotSet);
+    }
+
+    /// Length of a full frame from `encode`: 8 header bytes, two VERBATIM
+    /// subframes and the CRC-16.
+    const FRAME_BYTES: usize = 8 + 2 * (1 + 2 * 4096) + 2;
+
+    /// Offset of the middle frame in `encode(3 * 4096, &[])`, after the metadata.
+    const MIDDLE_FRAME: usize = 42 + FRAME_BYTES;
+
+    /// The samples without the middle frame.
+    fn without_middle_frame(channels: &[Vec<i32>]) -> Vec<Vec<i32>> {
+        channels.iter().map(|c| c[..4096].iter().chain(c[8192..].iter()).cloned().collect()).collect()
+    }
+
+    #[test]
+    fn fails_on_a_damaged_frame() {
+        // Setting the padding bit of the first subframe breaks its structure,
+        // but it is still reported as the damage it is
+        let (mut bytes, _) = encode(3 * 4096, &[]);
+        bytes[MIDDLE_FRAME + 8] ^= 0x80;
+        match Flac::parse_with(&mut Cursor::new(bytes), CrcCheck::Fail) {
+            Err(SurfboardError::CrcMismatch { offset, what, .. }) => {
+                assert_eq!(offset, MIDDLE_FRAME as u64);
+                assert_eq!(what, "frame CRC-16");
+            },
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn warns_about_damaged_frames() {
+        // A flipped sample decodes, so the frame is kept as it is
+        let (mut bytes, mut channels) = encode(3 * 4096, &[]);
+        bytes[MIDDLE_FRAME + 9] ^= 0x01;
+        channels[0][4096] ^= 0x100;
+        let flac = Flac::parse_with(&mut Cursor::new(bytes), CrcCheck::Warn).unwrap();
+        assert_eq!(flac.samples(), channels);
+        assert_eq!(flac.errors.len(), 1);
+
+        // A broken subframe cannot be decoded, so the frame is dropped
+        let (mut bytes, channels) = encode(3 * 4096, &[]);
+        bytes[MIDDLE_FRAME + 8] ^= 0x80;
+        let flac = Flac::parse_with(&mut Cursor::new(bytes), CrcCheck::Warn).unwrap();
+        assert_eq!(flac.samples(), without_middle_frame(&channels));
+        assert_eq!(flac.errors.len(), 1);
+        match flac.errors[0] {
+            SurfboardError::CrcMismatch { offset, .. } => assert_eq!(offset, MIDDLE_FRAME as u64),
+            ref e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn skips_damaged_frames() {
+        for &(byte, mask) in [(MIDDLE_FRAME + 9, 0x01), (MIDDLE_FRAME + 8, 0x80)].iter() {
+            let (mut bytes, channels) = encode(3 * 4096, &[]);
+            bytes[byte] ^= mask;
+            let flac = Flac::parse_with(&mut Cursor::new(bytes), CrcCheck::Skip).unwrap();
+            assert_eq!(flac.samples(), without_middle_frame(&channels));
+            assert_eq!(flac.errors.len(), 1);
+            match flac.errors[0] {
+                SurfboardError::CrcMismatch { offset, .. } => assert_eq!(offset, MIDDLE_FRAME as u64),
+                ref e => panic!("{:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn seeks_past_false_sync_codes() {
+        // Hide a header for frame 0 with a valid CRC-8 among the left samples
+        // of frame 20, where the first bisection step starts looking
+        let (mut bytes, channels) = encode(40 * 4096 + 2048, &[]);
+        let frame = 42 + 20 * FRAME_BYTES;
+        let mid = 42 + (bytes.len() - 42) / 2;
+        let pos = mid + (mid - frame - 9) % 2;
+        assert!(pos + 8 <= frame + 9 + 2 * 4096);
+
+        let mut fake = vec![0xFF, 0xF8, 0x79, 0x18, 0x00, 0x0F, 0xFF];
+        let crc = crc8(&fake);
+        fake.push(crc);
+        for (b, &f) in bytes[pos..pos + 8].iter_mut().zip(fake.iter()) {
+            *b = f;
+        }
+        let end = frame + FRAME_BYTES - 2;
+        let crc = crc16(&bytes[frame..end]);
+        bytes[end] = (crc >> 8) as u8;
+        bytes[end + 1] = crc as u8;
+
+        let mut reader = FlacReader::new(Cursor::new(bytes)).unwrap();
+        let n = 21 * 4096 + 100;
+        reader.seek_to_sample(n as u64).unwrap();
+        let rest = decode_rest(&mut reader);
+        assert_eq!(&rest[0][..], &channels[0][n..]);
+        assert_eq!(&rest[1][..], &channels[1][n..]);
+    }
+
+    /// Decodes from the reader's position to the end.
+    fn decode_rest<R: io::Read + io::Seek>(reader: &mut FlacReader<R>) -> Vec<Vec<i32>> {
+        let mut channels = vec![Vec::new(), Vec::new()];
+        while let Some(block) = reader.next_block().unwrap() {
+            for (channel, samples) in channels.iter_mut().zip(block) {
+                channel.extend(samples);
+            }
+        }
+        channels
+    }
+
+    #[test]
+    fn seeks_to_any_sample() {
+        // Long enough for the search to bisect before decoding forward
+        let length = 150000;
+        let (bytes, channels) = encode(length, &[]);
+
+        // The same audio behind a seek table pointing at every fifth frame
+        let mut reader = FlacReader::new(Cursor::new(bytes.clone())).unwrap();
+        let mut points = Vec::new();
+        let mut sample_number = 0;
+        loop {
+            let pos = reader.r.seek(io::SeekFrom::Current(0)).unwrap();
+            let block = match reader.next_block().unwrap() {
+                Some(block) => block,
+                None => break,
+            };
+            if points.len() * 5 * 4096 == sample_number as usize {
+                points.push(SeekPoint {
+                    sample_number: sample_number,
+                    stream_offset: pos - reader.audio_offset,
+                    frame_samples: block[0].len() as u16,
+                });
+            }
+            sample_number += block[0].len() as u64;
+        }
+        points.push(SeekPoint { sample_number: 0xFFFFFFFFFFFFFFFF, stream_offset: 0, frame_samples: 0 });
+        let (with_table, _) = encode(length, &points);
+
+        for bytes in vec![bytes, with_table] {
+            let mut reader = FlacReader::new(Cursor::new(bytes)).unwrap();
+            for &n in [0, 1, 4095, 4096, 20481, 77777, 81920, length - 1].iter() {
+                reader.seek_to_sample(n as u64).unwrap();
+                let rest = decode_rest(&mut reader);
+                assert_eq!(rest[0], &channels[0][n..]);
+                assert_eq!(rest[1], &channels[1][n..]);
+            }
+            for &n in [length as u64, length as u64 + 4096].iter() {
+                match reader.seek_to_sample(n) {
+                    Err(SurfboardError::SeekPastEnd { sample, total }) => assert_eq!((sample, total), (n, length as u64)),
+                    result => panic!("{:?}", result),
+                }
+            }
+        }
+    }
+
+    /// A metadata block header and `payload`.
+    fn block(last: bool, code: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_u32::<BigEndian>((last as u32) << 31 | (code as u32) << 24 | payload.len() as u32).unwrap();
+        bytes.extend(payload.iter().cloned());
+        bytes
+    }
+
+    /// Parses `bytes` as a block and checks that writing it back gives the
+    /// same bytes.
+    fn round_trip(bytes: &[u8]) -> BlockType {
+        let block = Block::parse(&mut Cursor::new(bytes)).unwrap();
+        let mut written = Vec::new();
+        block.write(&mut written, block.last_meta).unwrap();
+        assert_eq!(&written[..], bytes);
+        block.type_data.unwrap()
+    }
+
+    #[test]
+    fn round_trips_picture_blocks() {
+        let mut payload = Vec::new();
+        payload.write_u32::<BigEndian>(3).unwrap();
+        payload.write_u32::<BigEndian>(9).unwrap();
+        payload.extend(b"image/png".iter().cloned());
+        let description = "Vorder\u{fc}";
+        payload.write_u32::<BigEndian>(description.len() as u32).unwrap();
+        payload.extend(description.as_bytes().iter().cloned());
+        for &field in [2, 1, 24, 0, 6].iter() {
+            payload.write_u32::<BigEndian>(field).unwrap();
+        }
+        payload.extend([0x89, 0x50, 0x4E, 0x47, 0x00, 0xFF].iter().cloned());
+
+        match round_trip(&block(false, 6, &payload)) {
+            BlockType::Picture(picture) => {
+                assert_eq!(picture.type_name(), "front cover");
+                assert_eq!(picture.mime_type, "image/png");
+                assert_eq!(picture.description, description);
+                assert_eq!((picture.width, picture.height, picture.depth, picture.colors), (2, 1, 24, 0));
+                assert_eq!(picture.data, vec![0x89, 0x50, 0x4E, 0x47, 0x00, 0xFF]);
+                assert!(!picture.is_link());
+            },
+            t => panic!("{:?}", t),
+        }
+
+        // A linked picture stores its URL as the data
+        let mut payload = Vec::new();
+        payload.write_u32::<BigEndian>(21).unwrap();
+        payload.write_u32::<BigEndian>(3).unwrap();
+        payload.extend(b"-->".iter().cloned());
+        payload.write_u32::<BigEndian>(0).unwrap();
+        for &field in [0, 0, 0, 0, 19].iter() {
+            payload.write_u32::<BigEndian>(field).unwrap();
+        }
+        payload.extend(b"http://example.com/".iter().cloned());
+
+        match round_trip(&block(true, 6, &payload)) {
+            BlockType::Picture(picture) => {
+                assert_eq!(picture.type_name(), "reserved");
+                assert!(picture.is_link());
+                assert_eq!(picture.data, b"http://example.com/".to_vec());
+            },
+            t => panic!("{:?}", t),
+        }
+    }
+
+    #[test]
+    fn round_trips_application_padding_and_reserved_blocks() {
+        let mut payload = b"ATCH".to_vec();
+        payload.extend([0x00, 0x01, 0xFE, 0xFF].iter().cloned());
+        match round_trip(&block(false, 2, &payload)) {
+            BlockType::Application { id, data } => {
+                assert_eq!(&id, b"ATCH");
+                assert_eq!(data, vec![0x00, 0x01, 0xFE, 0xFF]);
+            },
+            t => panic!("{:?}", t),
+        }
+
+        match round_trip(&block(false, 1, &[0; 10])) {
+            BlockType::Padding(length) => assert_eq!(length, 10),
+            t => panic!("{:?}", t),
+        }
+
+        // Reserved types, the last one with no payload at all
+        for &(code, ref payload) in [(7, vec![1, 2, 3]), (126, Vec::new())].iter() {
+            match round_trip(&block(true, code, payload)) {
+                BlockType::Other { code: c, data } => {
+                    assert_eq!(c, code);
+                    assert_eq!(&data, payload);
+                },
+                t => panic!("{:?}", t),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_cuesheet_blocks() {
+        let mut payload = b"1234567890123".to_vec();
+        payload.extend(vec![0; 128 - 13]);
+        payload.write_u64::<BigEndian>(88200).unwrap();
+        payload.push(0x80);
+        payload.extend(vec![0; 258]);
+        payload.push(2);
+
+        // A pre-emphasised audio track with two index points
+        payload.write_u64::<BigEndian>(0).unwrap();
+        payload.push(1);
+        payload.extend(b"USABC1234567".iter().cloned());
+        payload.push(0x40);
+        payload.extend(vec![0; 13]);
+        payload.push(2);
+        for &(offset, number) in [(0, 0), (588, 1)].iter() {
+            payload.write_u64::<BigEndian>(offset).unwrap();
+            payload.push(number);
+            payload.extend(vec![0; 3]);
+        }
+
+        // The lead-out, marked as data, with no ISRC or index points
+        payload.write_u64::<BigEndian>(441000).unwrap();
+        payload.push(170);
+        payload.extend(vec![0; 12]);
+        payload.push(0x80);
+        payload.extend(vec![0; 13]);
+        payload.push(0);
+
+        match round_trip(&block(false, 5, &payload)) {
+            BlockType::CueSheet(cue) => {
+                assert_eq!(cue.media_catalog_number, "1234567890123");
+                assert_eq!(cue.lead_in, 88200);
+                assert!(cue.is_cd);
+                assert_eq!(cue.tracks.len(), 2);
+
+                let track = &cue.tracks[0];
+                assert_eq!((track.offset, track.number), (0, 1));
+                assert_eq!(track.isrc, "USABC1234567");
+                assert!(track.is_audio && track.pre_emphasis);
+                assert_eq!(track.indices, vec![CueIndex { offset: 0, number: 0 }, CueIndex { offset: 588, number: 1 }]);
+
+                let track = &cue.tracks[1];
+                assert_eq!((track.offset, track.number), (441000, 170));
+                assert_eq!(track.isrc, "");
+                assert!(!track.is_audio && !track.pre_emphasis);
+                assert!(track.indices.is_empty());
+            },
+            t => panic!("{:?}", t),
         }
     }
 }
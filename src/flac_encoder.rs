@@ -0,0 +1,674 @@
+// FLAC encoding: fixed and LPC predictors, partitioned Rice residuals and
+// stereo decorrelation, as described in https://xiph.org/flac/format.html
+
+use std::cmp;
+use std::io;
+use std::mem;
+use num::bigint::BigUint;
+use bitwriter::BitWriter;
+use crc::{crc8, crc16};
+use error::{SurfboardError, Result, tell};
+use flac::{Block, BlockType};
+use lpc;
+use md5::Md5;
+
+/// How hard the encoder searches for a compact encoding.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncoderConfig {
+    /// Samples per channel in each frame.
+    pub block_size: u16,
+    /// Highest fixed predictor order tried, at most 4.
+    pub max_fixed_order: u32,
+    /// Highest LPC order tried, at most 32. 0 disables LPC.
+    pub max_lpc_order: u32,
+    /// Bits per quantized LPC coefficient, at most 15. 0 picks one from the
+    /// sample size and block size.
+    pub qlp_precision: u32,
+    /// Encode every LPC order up to `max_lpc_order` and keep the smallest,
+    /// rather than only the order the prediction error points to.
+    pub exhaustive_lpc_search: bool,
+    /// Highest Rice partition order tried, at most 15.
+    pub max_partition_order: u32,
+    /// Try left/side, side/right and mid/side as well as independent
+    /// channels for stereo input.
+    pub stereo_decorrelation: bool,
+}
+
+impl EncoderConfig {
+    /// Presets in the spirit of the reference encoder's `-0` to `-8`.
+    /// Higher levels compress better and encode more slowly.
+    pub fn level(level: u8) -> EncoderConfig {
+        let (block_size, max_lpc_order, max_partition_order, stereo_decorrelation, exhaustive_lpc_search) = match level {
+            0 => (1152, 0, 3, false, false),
+            1 => (1152, 0, 3, true, false),
+            2 => (1152, 0, 3, true, false),
+            3 => (4096, 6, 4, false, false),
+            4 => (4096, 8, 4, true, false),
+            5 => (4096, 8, 5, true, false),
+            6 => (4096, 8, 6, true, false),
+            7 => (4096, 12, 6, true, false),
+            _ => (4096, 12, 6, true, true),
+        };
+        EncoderConfig {
+            block_size: block_size,
+            max_fixed_order: if level == 0 { 2 } else { 4 },
+            max_lpc_order: max_lpc_order,
+            qlp_precision: 0,
+            exhaustive_lpc_search: exhaustive_lpc_search,
+            max_partition_order: max_partition_order,
+            stereo_decorrelation: stereo_decorrelation,
+        }
+    }
+}
+
+impl Default for EncoderConfig {
+    fn default() -> EncoderConfig {
+        EncoderConfig::level(5)
+    }
+}
+
+/// Writes a FLAC stream from interleaved samples.
+///
+/// STREAMINFO is written up front with placeholder values and filled in by
+/// `finish`, which is why the output has to be seekable.
+pub struct FlacEncoder<W: io::Write + io::Seek> {
+    w: W,
+    config: EncoderConfig,
+    sample_rate: u32,
+    num_channels: u8,
+    bits_per_sample: u8,
+    stream_info_offset: u64,
+    /// Whether STREAMINFO is the only metadata block.
+    stream_info_last: bool,
+    /// Samples waiting for a full block, one vector per channel.
+    pending: Vec<Vec<i64>>,
+    frame_number: u64,
+    total_samples: u64,
+    min_frame_size: u32,
+    max_frame_size: u32,
+    md5: Md5,
+    /// LPC analysis window, kept between frames of the same size.
+    window: Vec<f64>,
+}
+
+impl<W: io::Write + io::Seek> FlacEncoder<W> {
+    pub fn new(w: W, sample_rate: u32, num_channels: u8, bits_per_sample: u8, config: EncoderConfig) -> Result<FlacEncoder<W>> {
+        FlacEncoder::with_blocks(w, sample_rate, num_channels, bits_per_sample, config, &[])
+    }
+
+    /// Like `new`, but writes `blocks`, such as Vorbis comments or pictures,
+    /// after STREAMINFO.
+    pub fn with_blocks(mut w: W, sample_rate: u32, num_channels: u8, bits_per_sample: u8, config: EncoderConfig,
+                       blocks: &[Block]) -> Result<FlacEncoder<W>> {
+        if num_channels < 1 || num_channels > 8 {
+            return Err(SurfboardError::Unsupported { offset: 0, what: "channel count", code: num_channels as u32 });
+        }
+        if bits_per_sample < 4 || bits_per_sample > 24 {
+            return Err(SurfboardError::Unsupported { offset: 0, what: "bits per sample", code: bits_per_sample as u32 });
+        }
+        if sample_rate == 0 || sample_rate > 655350 {
+            return Err(SurfboardError::Unsupported { offset: 0, what: "sample rate", code: sample_rate });
+        }
+        if config.block_size < 16 || config.max_fixed_order > 4 || config.max_lpc_order > lpc::MAX_ORDER as u32 ||
+           config.qlp_precision > 15 || config.max_partition_order > 15 {
+            return Err(SurfboardError::Malformed { offset: 0, what: "encoder configuration" });
+        }
+
+        let start = try!(tell(&mut w));
+        try!(w.write_all(b"fLaC"));
+
+        let mut encoder = FlacEncoder {
+            w: w,
+            config: config,
+            sample_rate: sample_rate,
+            num_channels: num_channels,
+            bits_per_sample: bits_per_sample,
+            stream_info_offset: start + 4,
+            stream_info_last: blocks.is_empty(),
+            pending: vec![Vec::new(); num_channels as usize],
+            frame_number: 0,
+            total_samples: 0,
+            min_frame_size: 0,
+            max_frame_size: 0,
+            md5: Md5::new(),
+            window: Vec::new(),
+        };
+        try!(encoder.write_stream_info([0; 16]));
+        for (i, block) in blocks.iter().enumerate() {
+            try!(block.write(&mut encoder.w, i == blocks.len() - 1));
+        }
+        Ok(encoder)
+    }
+
+    /// Queues interleaved samples, writing a frame for every full block.
+    pub fn write_samples(&mut self, samples: &[i32]) -> Result<()> {
+        let num_channels = self.num_channels as usize;
+        if samples.len() % num_channels != 0 {
+            return Err(SurfboardError::Malformed { offset: 0, what: "interleaved samples, not a whole number of frames" });
+        }
+
+        // Check every sample before queueing any, so a bad slice changes nothing
+        let limit = 1i64 << (self.bits_per_sample - 1);
+        if samples.iter().any(|&sample| (sample as i64) < -limit || sample as i64 >= limit) {
+            return Err(SurfboardError::Malformed { offset: 0, what: "sample out of range for bits per sample" });
+        }
+
+        let bytes_per_sample = (self.bits_per_sample as usize + 7) / 8;
+        let mut buf = Vec::with_capacity(samples.len() * bytes_per_sample);
+        for (i, &sample) in samples.iter().enumerate() {
+            let sample = sample as i64;
+            for b in 0..bytes_per_sample {
+                buf.push((sample >> (8 * b)) as u8);
+            }
+            self.pending[i % num_channels].push(sample);
+        }
+        self.md5.update(&buf);
+        self.total_samples += (samples.len() / num_channels) as u64;
+
+        let block_size = self.config.block_size as usize;
+        while self.pending[0].len() >= block_size {
+            let block: Vec<Vec<i64>> = self.pending.iter_mut().map(|c| {
+                let rest = c.split_off(block_size);
+                mem::replace(c, rest)
+            }).collect();
+            try!(self.write_frame(&block));
+        }
+        Ok(())
+    }
+
+    /// Writes the last, possibly short, frame and completes STREAMINFO.
+    pub fn finish(mut self) -> Result<W> {
+        if !self.pending[0].is_empty() {
+            let block: Vec<Vec<i64>> = self.pending.iter_mut().map(|c| mem::replace(c, Vec::new())).collect();
+            try!(self.write_frame(&block));
+        }
+
+        let end = try!(tell(&mut self.w));
+        let md5 = mem::replace(&mut self.md5, Md5::new()).finish();
+        try!(self.w.seek(io::SeekFrom::Start(self.stream_info_offset)));
+        try!(self.write_stream_info(md5));
+        try!(self.w.seek(io::SeekFrom::Start(end)));
+        try!(self.w.flush());
+        Ok(self.w)
+    }
+
+    fn write_stream_info(&mut self, md5: [u8; 16]) -> Result<()> {
+        let block = try!(Block::new(BlockType::StreamInfo {
+            min_block_size: self.config.block_size,
+            max_block_size: self.config.block_size,
+            min_frame_size: self.min_frame_size,
+            max_frame_size: self.max_frame_size,
+            sample_rate: self.sample_rate,
+            num_channels: self.num_channels,
+            bits_per_sample: self.bits_per_sample,
+            total_samples: self.total_samples,
+            sig: BigUint::from_bytes_be(&md5),
+        }));
+        block.write(&mut self.w, self.stream_info_last)
+    }
+
+    fn write_frame(&mut self, channels: &[Vec<i64>]) -> Result<()> {
+        let block_size = channels[0].len();
+        let bps = self.bits_per_sample as u32;
+        if self.config.max_lpc_order > 0 && self.window.len() != block_size {
+            self.window = lpc::tukey_window(block_size);
+        }
+        let window = &self.window;
+
+        // Pick the channel assignment whose subframes come out smallest
+        let (assignment, subframes) = if channels.len() == 2 && self.config.stereo_decorrelation {
+            let side: Vec<i64> = channels[0].iter().zip(channels[1].iter()).map(|(l, r)| l - r).collect();
+            let mid: Vec<i64> = channels[0].iter().zip(channels[1].iter()).map(|(l, r)| (l + r) >> 1).collect();
+            let left = plan_subframe(&channels[0], bps, &self.config, window);
+            let right = plan_subframe(&channels[1], bps, &self.config, window);
+            let side = plan_subframe(&side, bps + 1, &self.config, window);
+            let mid = plan_subframe(&mid, bps, &self.config, window);
+
+            let costs = [left.bits + right.bits, left.bits + side.bits, side.bits + right.bits, mid.bits + side.bits];
+            let best = (1..4).fold(0, |best, i| if costs[i] < costs[best] { i } else { best });
+            match best {
+                0 => (1, vec![left, right]),
+                1 => (8, vec![left, side]),
+                2 => (9, vec![side, right]),
+                _ => (10, vec![mid, side]),
+            }
+        } else {
+            let plans = channels.iter().map(|c| plan_subframe(c, bps, &self.config, window)).collect();
+            (channels.len() as u32 - 1, plans)
+        };
+
+        let mut bits = BitWriter::new();
+        self.write_frame_header(&mut bits, block_size, assignment);
+        for subframe in subframes.iter() {
+            subframe.write(&mut bits);
+        }
+        bits.align();
+
+        let mut frame = bits.into_bytes();
+        let crc = crc16(&frame);
+        frame.push((crc >> 8) as u8);
+        frame.push(crc as u8);
+        try!(self.w.write_all(&frame));
+
+        let size = frame.len() as u32;
+        if self.frame_number == 0 || size < self.min_frame_size {
+            self.min_frame_size = size;
+        }
+        if size > self.max_frame_size {
+            self.max_frame_size = size;
+        }
+        self.frame_number += 1;
+        Ok(())
+    }
+
+    fn write_frame_header(&self, bits: &mut BitWriter, block_size: usize, assignment: u32) {
+        // Sync code and reserved bit, fixed blocksize strategy
+        bits.write_bits(0xFFF8, 16);
+
+        let (size_code, size_tail) = match block_size {
+            192 => (1, None),
+            576 | 1152 | 2304 | 4608 => (2 + (block_size / 576).trailing_zeros(), None),
+            256 | 512 | 1024 | 2048 | 4096 | 8192 | 16384 | 32768 => (8 + (block_size / 256).trailing_zeros(), None),
+            n if n <= 256 => (6, Some((n as u64 - 1, 8))),
+            n => (7, Some((n as u64 - 1, 16))),
+        };
+        let (rate_code, rate_tail) = match self.sample_rate {
+            88200 => (1, None),
+            176400 => (2, None),
+            192000 => (3, None),
+            8000 => (4, None),
+            16000 => (5, None),
+            22050 => (6, None),
+            24000 => (7, None),
+            32000 => (8, None),
+            44100 => (9, None),
+            48000 => (10, None),
+            96000 => (11, None),
+            r if r % 1000 == 0 && r / 1000 < 256 => (12, Some((r as u64 / 1000, 8))),
+            r if r < 65536 => (13, Some((r as u64, 16))),
+            r if r % 10 == 0 && r / 10 < 65536 => (14, Some((r as u64 / 10, 16))),
+            _ => (0, None),
+        };
+        let size_bits_code = match self.bits_per_sample {
+            8 => 1,
+            12 => 2,
+            16 => 4,
+            20 => 5,
+            24 => 6,
+            _ => 0,
+        };
+
+        bits.write_bits(size_code as u64, 4);
+        bits.write_bits(rate_code, 4);
+        bits.write_bits(assignment as u64, 4);
+        bits.write_bits(size_bits_code, 3);
+        bits.write_bits(0, 1);
+        for byte in encode_utf8_val(self.frame_number) {
+            bits.write_bits(byte as u64, 8);
+        }
+        if let Some((value, n)) = size_tail {
+            bits.write_bits(value, n);
+        }
+        if let Some((value, n)) = rate_tail {
+            bits.write_bits(value, n);
+        }
+
+        let crc = crc8(bits.bytes());
+        bits.write_bits(crc as u64, 8);
+    }
+}
+
+/// The UTF-8 style variable length coding used for frame numbers.
+fn encode_utf8_val(value: u64) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+
+    let mut length = 2;
+    while value >= 1u64 << (5 * length + 1) {
+        length += 1;
+    }
+
+    let mut bytes = Vec::with_capacity(length);
+    let lead = (0xFF00u16 >> length) as u8;
+    bytes.push(lead | (value >> (6 * (length - 1))) as u8);
+    for i in (0..length - 1).rev() {
+        bytes.push(0x80 | ((value >> (6 * i)) & 0x3F) as u8);
+    }
+    bytes
+}
+
+const FIXED_COEFS: [&'static [i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+
+#[derive(Debug)]
+enum Predictor {
+    Constant,
+    Verbatim,
+    Fixed(u32),
+    Lpc { coefs: Vec<i64>, precision: u32, shift: u32 },
+}
+
+/// Rice coding chosen for a residual.
+#[derive(Debug)]
+struct RicePlan {
+    partition_order: u32,
+    params: Vec<u32>,
+}
+
+/// A subframe as it will be written, with its size in bits.
+#[derive(Debug)]
+struct SubframePlan {
+    predictor: Predictor,
+    wasted_bits: u32,
+    /// Bits per sample after removing wasted bits.
+    bps: u32,
+    samples: Vec<i64>,
+    residual: Vec<i64>,
+    rice: Option<RicePlan>,
+    bits: u64,
+}
+
+impl SubframePlan {
+    fn write(&self, bits: &mut BitWriter) {
+        bits.write_bits(0, 1);
+        let type_code = match self.predictor {
+            Predictor::Constant => 0,
+            Predictor::Verbatim => 1,
+            Predictor::Fixed(order) => 8 | order,
+            Predictor::Lpc { ref coefs, .. } => 32 | (coefs.len() as u32 - 1),
+        };
+        bits.write_bits(type_code as u64, 6);
+        if self.wasted_bits > 0 {
+            bits.write_bits(1, 1);
+            bits.write_unary(self.wasted_bits - 1);
+        } else {
+            bits.write_bits(0, 1);
+        }
+
+        match self.predictor {
+            Predictor::Constant => bits.write_signed(self.samples[0], self.bps),
+            Predictor::Verbatim => {
+                for &sample in self.samples.iter() {
+                    bits.write_signed(sample, self.bps);
+                }
+            },
+            Predictor::Fixed(order) => {
+                for &sample in self.samples[..order as usize].iter() {
+                    bits.write_signed(sample, self.bps);
+                }
+                if let Some(ref rice) = self.rice {
+                    write_residual(bits, &self.residual, order as usize, self.samples.len(), rice);
+                }
+            },
+            Predictor::Lpc { ref coefs, precision, shift } => {
+                for &sample in self.samples[..coefs.len()].iter() {
+                    bits.write_signed(sample, self.bps);
+                }
+                bits.write_bits(precision as u64 - 1, 4);
+                bits.write_signed(shift as i64, 5);
+                for &coef in coefs.iter() {
+                    bits.write_signed(coef, precision);
+                }
+                if let Some(ref rice) = self.rice {
+                    write_residual(bits, &self.residual, coefs.len(), self.samples.len(), rice);
+                }
+            },
+        }
+    }
+}
+
+/// Works out the smallest subframe for `samples` within what `config` allows.
+fn plan_subframe(samples: &[i64], bps: u32, config: &EncoderConfig, window: &[f64]) -> SubframePlan {
+    // Bits that are zero in every sample need not be stored
+    let wasted_bits = match samples.iter().fold(0, |acc, &s| acc | s) {
+        0 => 0,
+        all => cmp::min(all.trailing_zeros(), bps - 1),
+    };
+    let shifted: Vec<i64> = samples.iter().map(|&s| s >> wasted_bits).collect();
+    let bps = bps - wasted_bits;
+    let header_bits = 8 + if wasted_bits > 0 { wasted_bits as u64 } else { 0 };
+
+    if shifted.iter().all(|&s| s == shifted[0]) {
+        return SubframePlan {
+            predictor: Predictor::Constant,
+            wasted_bits: wasted_bits,
+            bps: bps,
+            samples: shifted,
+            residual: Vec::new(),
+            rice: None,
+            bits: header_bits + bps as u64,
+        };
+    }
+
+    let mut best = SubframePlan {
+        predictor: Predictor::Verbatim,
+        wasted_bits: wasted_bits,
+        bps: bps,
+        bits: header_bits + bps as u64 * shifted.len() as u64,
+        samples: Vec::new(),
+        residual: Vec::new(),
+        rice: None,
+    };
+
+    let max_order = cmp::min(config.max_fixed_order as usize, shifted.len() - 1);
+    for order in 0..max_order + 1 {
+        let residual = fixed_residual(&shifted, order);
+        let (rice, residual_bits) = plan_rice(&residual, order, shifted.len(), config.max_partition_order);
+        let bits = header_bits + (order as u64) * bps as u64 + residual_bits;
+        if bits < best.bits {
+            best.predictor = Predictor::Fixed(order as u32);
+            best.bits = bits;
+            best.residual = residual;
+            best.rice = Some(rice);
+        }
+    }
+
+    let max_order = cmp::min(config.max_lpc_order as usize, shifted.len() - 1);
+    if max_order > 0 {
+        let autoc = lpc::autocorrelation(&shifted, window, max_order);
+        let orders = lpc::levinson_durbin(&autoc);
+        let precision = if config.qlp_precision > 0 {
+            config.qlp_precision
+        } else {
+            default_qlp_precision(bps, shifted.len())
+        };
+
+        // Without an exhaustive search, only try the order whose prediction
+        // error promises the smallest subframe
+        let candidates: Vec<usize> = if config.exhaustive_lpc_search {
+            (1..orders.len() + 1).collect()
+        } else {
+            let estimate = |order: usize| {
+                let err = orders[order - 1].1;
+                let per_sample = lpc::expected_bits_per_sample(err, shifted.len());
+                (order as u32 * (bps + precision)) as f64 + per_sample * (shifted.len() - order) as f64
+            };
+            (1..orders.len() + 1).fold(None, |best: Option<usize>, order| match best {
+                Some(b) if estimate(b) <= estimate(order) => Some(b),
+                _ => Some(order),
+            }).into_iter().collect()
+        };
+
+        for order in candidates {
+            let (coefs, shift) = match lpc::quantize(&orders[order - 1].0, precision) {
+                Some(quantized) => quantized,
+                None => continue,
+            };
+            let residual = lpc::residual(&shifted, &coefs, shift);
+            // Decoders keep residuals in 32 bits
+            if residual.iter().any(|&r| r >= 1 << 31 || r < -(1 << 31)) {
+                continue;
+            }
+            let (rice, residual_bits) = plan_rice(&residual, order, shifted.len(), config.max_partition_order);
+            let bits = header_bits + (order as u64) * (bps + precision) as u64 + 4 + 5 + residual_bits;
+            if bits < best.bits {
+                best.predictor = Predictor::Lpc { coefs: coefs, precision: precision, shift: shift };
+                best.bits = bits;
+                best.residual = residual;
+                best.rice = Some(rice);
+            }
+        }
+    }
+
+    best.samples = shifted;
+    best
+}
+
+/// Coefficient precision the reference encoder picks for a sample size and
+/// block size.
+fn default_qlp_precision(bps: u32, block_size: usize) -> u32 {
+    if bps < 16 {
+        cmp::max(5, 2 + bps / 2)
+    } else if bps == 16 {
+        match block_size {
+            0...192 => 7,
+            193...384 => 8,
+            385...576 => 9,
+            577...1152 => 10,
+            1153...2304 => 11,
+            2305...4608 => 12,
+            _ => 13,
+        }
+    } else if block_size <= 384 {
+        13
+    } else if block_size <= 1152 {
+        14
+    } else {
+        15
+    }
+}
+
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    let coefs = FIXED_COEFS[order];
+    (order..samples.len()).map(|i| {
+        let prediction = coefs.iter().enumerate().fold(0, |acc, (j, c)| acc + c * samples[i - j - 1]);
+        samples[i] - prediction
+    }).collect()
+}
+
+/// Chooses the partition order and Rice parameters for `residual`, returning
+/// the plan and its estimated size in bits, coding method included.
+fn plan_rice(residual: &[i64], order: usize, block_size: usize, max_partition_order: u32) -> (RicePlan, u64) {
+    let folded: Vec<u64> = residual.iter().map(|&r| ((r << 1) ^ (r >> 63)) as u64).collect();
+
+    let mut best: Option<(RicePlan, u64)> = None;
+    for partition_order in 0..max_partition_order + 1 {
+        let partitions = 1 << partition_order;
+        if block_size % partitions != 0 || block_size / partitions <= order {
+            break;
+        }
+        let partition_size = block_size / partitions;
+
+        let mut params = Vec::with_capacity(partitions);
+        let mut bits = 0;
+        let mut start = 0;
+        for p in 0..partitions {
+            let count = if p == 0 { partition_size - order } else { partition_size };
+            let sum = folded[start..start + count].iter().fold(0, |acc, &u| acc + u);
+            let (param, cost) = best_rice_param(sum, count as u64);
+            params.push(param);
+            bits += cost;
+            start += count;
+        }
+
+        // Parameters above 14 need the 5-bit RICE2 method
+        let param_bits = if params.iter().any(|&k| k > 14) { 5 } else { 4 };
+        bits += 2 + 4 + param_bits * partitions as u64;
+
+        if best.as_ref().map_or(true, |b| bits < b.1) {
+            best = Some((RicePlan { partition_order: partition_order, params: params }, bits));
+        }
+    }
+    best.unwrap()
+}
+
+/// The Rice parameter minimising the estimated size of `count` values
+/// adding up to `sum` once folded.
+fn best_rice_param(sum: u64, count: u64) -> (u32, u64) {
+    let mut best = (0, count + sum);
+    for param in 1..31 {
+        let cost = count * (param as u64 + 1) + (sum >> param);
+        if cost < best.1 {
+            best = (param, cost);
+        }
+    }
+    best
+}
+
+fn write_residual(bits: &mut BitWriter, residual: &[i64], order: usize, block_size: usize, rice: &RicePlan) {
+    let rice2 = rice.params.iter().any(|&k| k > 14);
+    bits.write_bits(if rice2 { 1 } else { 0 }, 2);
+    bits.write_bits(rice.partition_order as u64, 4);
+
+    let partition_size = block_size >> rice.partition_order;
+    let mut start = 0;
+    for (p, &param) in rice.params.iter().enumerate() {
+        let count = if p == 0 { partition_size - order } else { partition_size };
+        bits.write_bits(param as u64, if rice2 { 5 } else { 4 });
+        for &value in residual[start..start + count].iter() {
+            bits.write_rice(value, param);
+        }
+        start += count;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use flac::{Flac, Md5Status};
+    use super::{EncoderConfig, FlacEncoder};
+
+    /// Two channels of a decaying tone with some noise, interleaved.
+    fn signal(length: usize) -> Vec<i32> {
+        let mut seed = 1u32;
+        let mut samples = Vec::with_capacity(length * 2);
+        for i in 0..length {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let noise = (seed >> 16) as i32 % 64 - 32;
+            let tone = ((i as f64 * 0.05).sin() * 20000.0 * (1.0 - i as f64 / length as f64)) as i32;
+            samples.push(tone + noise);
+            samples.push(tone / 2 - noise);
+        }
+        samples
+    }
+
+    fn deinterleave(samples: &[i32]) -> Vec<Vec<i32>> {
+        let mut channels = vec![Vec::new(), Vec::new()];
+        for (i, &sample) in samples.iter().enumerate() {
+            channels[i % 2].push(sample);
+        }
+        channels
+    }
+
+    #[test]
+    fn round_trips_through_the_decoder() {
+        let samples = signal(10000);
+        for &level in [0, 5, 8].iter() {
+            let mut encoder = FlacEncoder::new(Cursor::new(Vec::new()), 44100, 2, 16, EncoderConfig::level(level)).unwrap();
+            // Uneven writes, some ending mid-block
+            for chunk in samples.chunks(2 * 777) {
+                encoder.write_samples(chunk).unwrap();
+            }
+            let bytes = encoder.finish().unwrap().into_inner();
+
+            let flac = Flac::parse(&mut Cursor::new(bytes)).unwrap();
+            assert_eq!(flac.samples(), deinterleave(&samples));
+            assert_eq!(flac.verify(), Md5Status::Match);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_slices_without_queueing_them() {
+        let mut encoder = FlacEncoder::new(Cursor::new(Vec::new()), 8000, 2, 8, EncoderConfig::default()).unwrap();
+        encoder.write_samples(&[1, -1, 2, -2]).unwrap();
+        // Out of range at the end, and an odd number of samples
+        assert!(encoder.write_samples(&[3, -3, 127, 128]).is_err());
+        assert!(encoder.write_samples(&[3, -3, 4]).is_err());
+        encoder.write_samples(&[-128, 127]).unwrap();
+        let bytes = encoder.finish().unwrap().into_inner();
+
+        let flac = Flac::parse(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(flac.samples(), vec![vec![1, 2, -128], vec![-1, -2, 127]]);
+        assert_eq!(flac.verify(), Md5Status::Match);
+    }
+}
@@ -0,0 +1,23 @@
+//! WAV and FLAC reading and writing.
+
+extern crate byteorder;
+extern crate num;
+
+mod error;
+mod crc;
+mod md5;
+mod lpc;
+pub mod bitreader;
+pub mod bitwriter;
+pub mod riff;
+pub mod wave;
+pub mod vorbis;
+pub mod flac;
+pub mod metadata;
+pub mod flac_encoder;
+
+pub use error::{SurfboardError, Result};
+pub use wave::{Wave, WavReader, WavWriter, WavStream};
+pub use flac::{Flac, FlacReader};
+pub use metadata::FlacMetadata;
+pub use flac_encoder::{FlacEncoder, EncoderConfig};
@@ -0,0 +1,198 @@
+// Linear prediction analysis for the FLAC encoder: windowed autocorrelation,
+// Levinson-Durbin recursion and coefficient quantization.
+
+use std::f64::consts::PI;
+
+/// Longest predictor FLAC can signal.
+pub const MAX_ORDER: usize = 32;
+
+/// A Tukey window with half its length tapered, as the reference encoder
+/// uses by default.
+pub fn tukey_window(n: usize) -> Vec<f64> {
+    let taper = n / 4;
+    (0..n).map(|i| {
+        if taper == 0 {
+            1.0
+        } else if i < taper {
+            0.5 - 0.5 * (PI * i as f64 / taper as f64).cos()
+        } else if i >= n - taper {
+            0.5 - 0.5 * (PI * (n - 1 - i) as f64 / taper as f64).cos()
+        } else {
+            1.0
+        }
+    }).collect()
+}
+
+/// Autocorrelation of `samples` weighted by `window`, for lags 0 to `max_lag`.
+pub fn autocorrelation(samples: &[i64], window: &[f64], max_lag: usize) -> Vec<f64> {
+    let data: Vec<f64> = samples.iter().zip(window.iter()).map(|(&s, &w)| s as f64 * w).collect();
+    (0..max_lag + 1).map(|lag| {
+        data[lag..].iter().zip(data.iter()).fold(0.0, |acc, (a, b)| acc + a * b)
+    }).collect()
+}
+
+/// Runs the Levinson-Durbin recursion on `autoc`, returning the predictor
+/// coefficients and prediction error for every order from 1 up to
+/// `autoc.len() - 1`. Stops early if the error reaches zero.
+///
+/// Coefficients are ordered as FLAC stores them: the first one weighs the
+/// most recent sample.
+pub fn levinson_durbin(autoc: &[f64]) -> Vec<(Vec<f64>, f64)> {
+    let max_order = autoc.len() - 1;
+    let mut lpc = vec![0.0; max_order];
+    let mut err = autoc[0];
+    let mut orders = Vec::with_capacity(max_order);
+
+    for i in 0..max_order {
+        if err <= 0.0 {
+            break;
+        }
+        let mut r = -autoc[i + 1];
+        for j in 0..i {
+            r -= lpc[j] * autoc[i - j];
+        }
+        r /= err;
+
+        lpc[i] = r;
+        for j in 0..i / 2 {
+            let tmp = lpc[j];
+            lpc[j] += r * lpc[i - 1 - j];
+            lpc[i - 1 - j] += r * tmp;
+        }
+        if i % 2 == 1 {
+            lpc[i / 2] += lpc[i / 2] * r;
+        }
+        err *= 1.0 - r * r;
+
+        orders.push((lpc[..i + 1].iter().map(|c| -c).collect(), err));
+    }
+    orders
+}
+
+/// Quantizes `coefs` to `precision` bit integers and a right shift, carrying
+/// the rounding error from one coefficient to the next. Returns `None` when
+/// the coefficients are too large or too small to express with a shift of
+/// 0 to 15.
+pub fn quantize(coefs: &[f64], precision: u32) -> Option<(Vec<i64>, u32)> {
+    let cmax = coefs.iter().fold(0.0f64, |acc, c| acc.max(c.abs()));
+    if cmax <= 0.0 {
+        return None;
+    }
+
+    let log2cmax = cmax.log2().floor() as i32;
+    let shift = precision as i32 - 2 - log2cmax;
+    if shift < 0 {
+        return None;
+    }
+    let shift = if shift > 15 { 15 } else { shift as u32 };
+
+    let qmax = (1i64 << (precision - 1)) - 1;
+    let qmin = -(1i64 << (precision - 1));
+    let mut error = 0.0;
+    let quantized = coefs.iter().map(|&c| {
+        error += c * (1i64 << shift) as f64;
+        let mut q = error.round() as i64;
+        if q > qmax {
+            q = qmax;
+        } else if q < qmin {
+            q = qmin;
+        }
+        error -= q as f64;
+        q
+    }).collect();
+    Some((quantized, shift))
+}
+
+/// Prediction residual of `samples` after the warm-up samples.
+pub fn residual(samples: &[i64], coefs: &[i64], shift: u32) -> Vec<i64> {
+    let order = coefs.len();
+    (order..samples.len()).map(|i| {
+        let prediction = coefs.iter().enumerate().fold(0, |acc, (j, c)| acc + c * samples[i - j - 1]);
+        samples[i] - (prediction >> shift)
+    }).collect()
+}
+
+/// Estimated bits per residual sample for a prediction error of `err` over
+/// `n` samples.
+pub fn expected_bits_per_sample(err: f64, n: usize) -> f64 {
+    if err <= 0.0 {
+        return 0.0;
+    }
+    let bits = 0.5 * (0.5 * err / n as f64).log2();
+    if bits > 0.0 { bits } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{autocorrelation, levinson_durbin, quantize};
+
+    fn assert_close(a: &[f64], b: &[f64], tolerance: f64) {
+        assert_eq!(a.len(), b.len());
+        for (x, y) in a.iter().zip(b.iter()) {
+            assert!((x - y).abs() < tolerance, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn solves_exact_autocorrelations() {
+        // First order decay: the second coefficient adds nothing
+        let orders = levinson_durbin(&[1.0, 0.5, 0.25]);
+        assert_eq!(orders.len(), 2);
+        assert_close(&orders[0].0, &[0.5], 1e-12);
+        assert_close(&orders[1].0, &[0.5, 0.0], 1e-12);
+        assert_close(&[orders[0].1, orders[1].1], &[0.75, 0.75], 1e-12);
+
+        // A constant signal is predicted perfectly at order 1
+        let orders = levinson_durbin(&[1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(orders.len(), 1);
+        assert_close(&orders[0].0, &[1.0], 1e-12);
+        assert_eq!(orders[0].1, 0.0);
+    }
+
+    #[test]
+    fn recovers_an_ar2_process() {
+        // x[n] = 1.6 x[n - 1] - 0.8 x[n - 2] + noise, with the noise from a
+        // small linear congruential generator
+        let mut state = 12345u32;
+        let mut samples = vec![0i64, 0];
+        for n in 2..20000 {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            let noise = (state >> 16) as i64 % 2001 - 1000;
+            let next = (1.6 * samples[n - 1] as f64 - 0.8 * samples[n - 2] as f64).round() as i64 + noise;
+            samples.push(next);
+        }
+
+        let window = vec![1.0; samples.len()];
+        let orders = levinson_durbin(&autocorrelation(&samples, &window, 4));
+        assert_eq!(orders.len(), 4);
+        assert_close(&orders[1].0, &[1.6, -0.8], 0.02);
+        assert_close(&orders[3].0, &[1.6, -0.8, 0.0, 0.0], 0.03);
+
+        // The error drops to the noise at order 2 and stays there
+        assert!(orders[1].1 < orders[0].1 / 2.0);
+        assert!(orders[3].1 > orders[1].1 * 0.99);
+    }
+
+    #[test]
+    fn quantizes_to_precision_and_shift() {
+        // The shift is as large as the largest coefficient allows
+        assert_eq!(quantize(&[0.5, -0.25], 15), Some((vec![8192, -4096], 14)));
+
+        // Rounding error carries over to the next coefficient
+        assert_eq!(quantize(&[0.3, 0.3, 0.3], 3), Some((vec![2, 3, 2], 3)));
+
+        // Tiny coefficients are limited to the largest shift FLAC can store
+        assert_eq!(quantize(&[0.001], 15), Some((vec![33], 15)));
+
+        // Too large for any shift, or nothing to quantize
+        assert_eq!(quantize(&[5000.0], 12), None);
+        assert_eq!(quantize(&[0.0, 0.0], 12), None);
+    }
+
+    #[test]
+    fn clamps_coefficients_to_precision() {
+        // 1.999 rounds to 8 at a shift of 2, one past the 4-bit maximum
+        assert_eq!(quantize(&[1.999, -1.999], 4), Some((vec![7, -7], 2)));
+        assert_eq!(quantize(&[-1.999], 4), Some((vec![-8], 2)));
+    }
+}
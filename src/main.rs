@@ -1,34 +1,394 @@
-mod wave;
-mod flac;
-
-extern crate byteorder;
 extern crate docopt;
 extern crate rustc_serialize;
-extern crate num;
+extern crate surfboard;
 
-use std::fs::File;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::Path;
+use std::io;
 use std::io::prelude::*;
-use byteorder::{LittleEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use std::process;
 use docopt::Docopt;
-use wave::Wave;
-use flac::Flac;
+use surfboard::{Wave, WavReader, WavWriter, Flac, FlacReader, FlacEncoder, EncoderConfig, SurfboardError};
+use surfboard::flac::{Block, BlockType, Md5Status, Picture};
+use surfboard::vorbis::VorbisComments;
+use surfboard::wave::{ChannelMask, Info, SampleFormat};
+
+fn print_wave(wave: &Wave) {
+    println!("size: {}", wave.chunk_size);
+    println!("audio format: {}", wave.fmt.audio_fmt);
+    println!("number of channels: {}", wave.fmt.num_channels);
+    println!("sample rate: {} Hz", wave.fmt.sample_rate);
+    println!("byte rate: {}", wave.fmt.byte_rate);
+    println!("block alignment: {}", wave.fmt.block_align);
+    println!("bits per sample: {}", wave.fmt.bits_per_sample);
+    if let Some(ref ext) = wave.fmt.extensible {
+        println!("valid bits per sample: {}", ext.valid_bits_per_sample);
+        println!("channel mask: 0x{:X} {:?}", ext.channel_mask.0, ext.channel_mask.speakers());
+        println!("sub format: {}", hex(&ext.sub_format));
+    }
+
+    if let Some(info) = wave.list.as_ref().and_then(|l| l.info.as_ref()) {
+        for (key, value) in info.data.iter() {
+            println!("{}: {}", key, value);
+        }
+    }
+
+    for chunk in wave.chunks.iter().chain(wave.trailing_chunks.iter()) {
+        println!("{} chunk: {} bytes", String::from_utf8_lossy(&chunk.id), chunk.data.len());
+    }
+
+    println!("data size: {}", wave.data.size);
+
+    let song_ms = wave.length_ms();
+    let song_min = song_ms / (60 * 1000);
+    let song_sec = song_ms / 1000;
+    println!("\nSong length: {}:{:0>2}", song_min, song_sec - (song_min * 60));
+}
+
+fn print_block(block_type: &BlockType) {
+    match *block_type {
+        BlockType::StreamInfo { min_block_size, max_block_size, min_frame_size, max_frame_size,
+                                sample_rate, num_channels, bits_per_sample, total_samples, ref sig } => {
+            println!("min blocksize: {} samples", min_block_size);
+            println!("max blocksize: {} samples", max_block_size);
+            println!("min framesize: {} bytes", min_frame_size);
+            println!("max framesize: {} bytes", max_frame_size);
+            println!("sample rate: {}", sample_rate);
+            println!("number of channels: {}", num_channels);
+            println!("bits per sample: {}", bits_per_sample);
+            println!("total samples: {}", total_samples);
+            println!("md5 sig: {:x}", sig);
+        },
+        BlockType::VorbisComment(ref comments) => {
+            println!("vendor string: {}", comments.vendor);
+            for &(ref name, ref value) in comments.iter() {
+                println!("{}: {}", name, value);
+            }
+        },
+        BlockType::Seektable(ref points) => {
+            for point in points.iter().filter(|p| !p.is_placeholder()) {
+                println!("sample {} at byte {} ({} samples)", point.sample_number, point.stream_offset, point.frame_samples);
+            }
+            println!("placeholders: {}", points.iter().filter(|p| p.is_placeholder()).count());
+        },
+        BlockType::Padding(length) => println!("padding: {} bytes", length),
+        BlockType::Application { ref id, ref data } =>
+            println!("application id: {} ({} bytes)", String::from_utf8_lossy(id), data.len()),
+        BlockType::CueSheet(ref cue) => {
+            println!("media catalog number: {}", cue.media_catalog_number);
+            println!("lead-in: {} samples", cue.lead_in);
+            println!("CD: {}", cue.is_cd);
+            for track in cue.tracks.iter() {
+                println!("track {} at sample {}{}{}", track.number, track.offset,
+                         if track.isrc.is_empty() { String::new() } else { format!(", ISRC {}", track.isrc) },
+                         if track.is_audio { "" } else { ", data" });
+                for index in track.indices.iter() {
+                    println!("    index {} at +{}", index.number, index.offset);
+                }
+            }
+        },
+        BlockType::Picture(ref picture) => print_picture(picture),
+        BlockType::Other { code, ref data } => println!("reserved block type {}: {} bytes", code, data.len()),
+    }
+}
+
+fn print_picture(picture: &Picture) {
+    if picture.is_link() {
+        println!("{}, linked to {}", picture.type_name(), String::from_utf8_lossy(&picture.data));
+    } else {
+        println!("{}, {}, {}x{}, {} bits per pixel, {} bytes",
+                 picture.type_name(), picture.mime_type, picture.width, picture.height, picture.depth, picture.data.len());
+    }
+    if !picture.description.is_empty() {
+        println!("description: {}", picture.description);
+    }
+}
+
+/// Lists the pictures embedded in a FLAC file, and writes them to `export`
+/// as `picture-<n>.<ext>` if given.
+fn flac_pictures<R: Read + Seek>(r: R, export: Option<&Path>) {
+    let reader = FlacReader::new(r).unwrap_or_else(|e| fail(e));
+    let pictures = reader.blocks.iter().flat_map(|blocks| blocks.iter()).filter_map(|block| match block.type_data {
+        Some(BlockType::Picture(ref picture)) => Some(picture),
+        _ => None,
+    });
+
+    for (i, picture) in pictures.enumerate() {
+        print!("{}: ", i + 1);
+        print_picture(picture);
+
+        if let Some(dir) = export {
+            if picture.is_link() {
+                continue;
+            }
+            let ext = match &*picture.mime_type {
+                "image/jpeg" | "image/jpg" => "jpg",
+                "image/png" => "png",
+                "image/gif" => "gif",
+                "image/bmp" => "bmp",
+                _ => "bin",
+            };
+            let path = dir.join(format!("picture-{}.{}", i + 1, ext));
+            if let Err(e) = File::create(&path).and_then(|mut f| f.write_all(&picture.data)) {
+                fail(SurfboardError::Io(e));
+            }
+            println!("exported to {}", path.display());
+        }
+    }
+}
+
+fn print_flac(flac: &Flac) {
+    let mut blocks = vec![&flac.stream_info];
+    if let Some(ref list) = flac.blocks {
+        blocks.extend(list.iter());
+    }
+
+    for block in blocks {
+        println!("\n{:?}", block.block_name);
+        println!("-----------------------------");
+        if let Some(ref block_type) = block.type_data {
+            print_block(block_type);
+        }
+    }
+
+    let samples = flac.frames.iter().fold(0, |n, f| n + f.header.block_size as u64);
+    println!("\nframes decoded: {}", flac.frames.len());
+    println!("samples decoded: {}", samples);
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a FLAC file and checks its MD5 signature, exiting non-zero on a mismatch.
+fn verify_flac<R: Read + Seek>(r: &mut R) {
+    let flac = Flac::parse(r).unwrap_or_else(|e| fail(e));
+    match flac.verify() {
+        Md5Status::Match => println!("MD5 match"),
+        Md5Status::NotSet => println!("MD5 signature not set"),
+        Md5Status::Mismatch { expected, computed } => {
+            println!("MD5 mismatch: stored {}, computed {}", hex(&expected), hex(&computed));
+            process::exit(1);
+        },
+    }
+}
+
+/// RIFF INFO ids and the Vorbis comment fields they correspond to.
+const INFO_TAGS: [(&'static str, &'static str); 9] = [
+    ("INAM", "TITLE"),
+    ("IART", "ARTIST"),
+    ("IPRD", "ALBUM"),
+    ("ICRD", "DATE"),
+    ("IGNR", "GENRE"),
+    ("ITRK", "TRACKNUMBER"),
+    ("ICMT", "COMMENT"),
+    ("ICOP", "COPYRIGHT"),
+    ("ISFT", "ENCODER"),
+];
+
+/// Adds a Vorbis comment for each INFO tag that has one.
+fn info_to_comments(info: &Info, comments: &mut VorbisComments) -> Result<(), SurfboardError> {
+    for &(id, field) in INFO_TAGS.iter() {
+        if let Some(text) = info.data.get(id) {
+            try!(comments.add(field, text));
+        }
+    }
+    Ok(())
+}
+
+/// INFO tags for the Vorbis comments that have one. INFO holds a single
+/// text per id, so repeated fields are joined.
+fn comments_to_info(comments: &VorbisComments) -> Info {
+    let mut data = HashMap::new();
+    for &(id, field) in INFO_TAGS.iter() {
+        let values = comments.get_all(field);
+        if !values.is_empty() {
+            data.insert(id.to_string(), values.join("; "));
+        }
+    }
+    Info { data: data }
+}
+
+/// Vorbis comment holding a WAV channel mask other than the usual one for
+/// the channel count.
+const CHANNEL_MASK_TAG: &'static str = "WAVEFORMATEXTENSIBLE_CHANNEL_MASK";
+
+/// Reads the first four bytes of `r` and rewinds it.
+fn read_magic<R: Read + Seek>(r: &mut R) -> io::Result<[u8; 4]> {
+    let mut magic = [0; 4];
+    try!(r.read_exact(&mut magic));
+    try!(r.seek(io::SeekFrom::Start(0)));
+    Ok(magic)
+}
+
+fn has_extension(path: &str, extensions: &[&str]) -> bool {
+    Path::new(path).extension().and_then(|e| e.to_str()).map_or(false, |e| {
+        extensions.iter().any(|x| x.eq_ignore_ascii_case(e))
+    })
+}
+
+/// Whether both paths name the same existing file.
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Converts between WAV and FLAC. The source format comes from its magic
+/// number, the destination format from its extension, defaulting to the
+/// other format.
+fn convert(source: &str, dest: &str, level: u8) -> Result<(), SurfboardError> {
+    // Creating the output would truncate the input before it is read
+    if same_file(source, dest) {
+        return Err(SurfboardError::Io(io::Error::new(io::ErrorKind::InvalidInput, "source and destination are the same file")));
+    }
+    let mut input = io::BufReader::new(try!(File::open(source)));
+    let magic = try!(read_magic(&mut input));
+    let to_flac = if has_extension(dest, &["flac"]) {
+        true
+    } else if has_extension(dest, &["wav", "wave"]) {
+        false
+    } else {
+        &magic == b"RIFF"
+    };
+
+    if &magic != b"RIFF" && &magic != b"fLaC" {
+        return Err(SurfboardError::BadMagic {
+            offset: 0,
+            expected: "RIFF or fLaC",
+            found: String::from_utf8_lossy(&magic).into_owned(),
+        });
+    }
+    if (&magic == b"RIFF") != to_flac {
+        try!(fs::copy(source, dest));
+        return Ok(());
+    }
+
+    let output = try!(File::create(dest));
+    let result = if to_flac {
+        wav_to_flac(input, output, level)
+    } else {
+        flac_to_wav(input, io::BufWriter::new(output))
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(dest);
+    }
+    result
+}
+
+fn wav_to_flac<R: Read + Seek>(r: R, w: File, level: u8) -> Result<(), SurfboardError> {
+    let mut reader = try!(WavReader::new(r));
+    let bits_per_sample = match reader.sample_format() {
+        SampleFormat::Int(bits) if bits <= 24 => bits,
+        format => return Err(SurfboardError::Unsupported { offset: 12, what: "sample format for FLAC", code: format.bits_per_sample() as u32 }),
+    };
+    if reader.fmt.num_channels > 8 {
+        return Err(SurfboardError::Unsupported { offset: 12, what: "channel count for FLAC", code: reader.fmt.num_channels as u32 });
+    }
+
+    let mut comments = VorbisComments::new(concat!("surfboard ", env!("CARGO_PKG_VERSION")));
+    if let Some(info) = reader.list.as_ref().and_then(|l| l.info.as_ref()) {
+        try!(info_to_comments(info, &mut comments));
+    }
+    // FLAC assumes the usual layout, so keep any other as the reference
+    // encoder does
+    let channel_mask = reader.fmt.channel_mask();
+    if channel_mask != ChannelMask::for_channels(reader.fmt.num_channels) {
+        try!(comments.set(CHANNEL_MASK_TAG, &format!("0x{:04X}", channel_mask.0)));
+    }
+    let blocks = [try!(Block::new(BlockType::VorbisComment(comments)))];
+
+    let mut encoder = try!(FlacEncoder::with_blocks(io::BufWriter::new(w), reader.fmt.sample_rate, reader.fmt.num_channels as u8,
+                                                    bits_per_sample as u8, EncoderConfig::level(level), &blocks));
+    let chunk = 4096 * reader.fmt.num_channels as usize;
+    let mut samples = Vec::with_capacity(chunk);
+    for sample in try!(reader.samples::<i32>()) {
+        samples.push(try!(sample));
+        if samples.len() == chunk {
+            try!(encoder.write_samples(&samples));
+            samples.clear();
+        }
+    }
+    try!(encoder.write_samples(&samples));
+    try!(encoder.finish());
+    Ok(())
+}
+
+fn flac_to_wav<R: Read + Seek, W: Write + Seek>(r: R, w: W) -> Result<(), SurfboardError> {
+    let mut reader = try!(FlacReader::new(r));
+    let (sample_rate, num_channels, bits_per_sample) = match reader.stream_info.type_data {
+        Some(BlockType::StreamInfo { sample_rate, num_channels, bits_per_sample, .. }) => (sample_rate, num_channels as u16, bits_per_sample as u16),
+        _ => unreachable!(),
+    };
+
+    let mut info = HashMap::new();
+    let mut channel_mask = None;
+    for block in reader.blocks.iter().flat_map(|blocks| blocks.iter()) {
+        if let Some(BlockType::VorbisComment(ref comments)) = block.type_data {
+            channel_mask = comments.get(CHANNEL_MASK_TAG)
+                .and_then(|v| u32::from_str_radix(v.trim_left_matches("0x").trim_left_matches("0X"), 16).ok())
+                .map(ChannelMask);
+            info.extend(comments_to_info(comments).data);
+        }
+    }
+
+    let mut writer = WavWriter::new()
+        .channels(num_channels)
+        .sample_rate(sample_rate)
+        .sample_format(SampleFormat::Int(bits_per_sample))
+        .info(Info { data: info });
+    if let Some(mask) = channel_mask {
+        writer = writer.channel_mask(mask);
+    }
+    let mut stream = try!(writer.start(w));
+
+    let mut samples = Vec::new();
+    while let Some(block) = try!(reader.next_block()) {
+        samples.clear();
+        for i in 0..block[0].len() {
+            for channel in block.iter() {
+                samples.push(channel[i]);
+            }
+        }
+        try!(stream.write_samples(&samples));
+    }
+    try!(stream.finalize());
+    Ok(())
+}
+
+fn fail(e: SurfboardError) -> ! {
+    let _ = writeln!(io::stderr(), "error: {}", e);
+    process::exit(1);
+}
 
 fn main() {
     const USAGE: &'static str = "
     Usage: surfboard -r <source>
        surfboard -w <source> <dest>
+       surfboard verify <source>
+       surfboard pictures <source> [--export=<dir>]
+       surfboard convert <source> <dest> [--level=<n>]
        surfboard -h
 
     Options:
-        -r, --read   Parse file.
-        -w, --write  Write data to file.
-        -h, --help   Show this message.
+        -r, --read      Parse file.
+        -w, --write     Write data to file.
+        -h, --help      Show this message.
+        --export=<dir>  Write embedded pictures into <dir>.
+        --level=<n>     FLAC compression level, 0 to 8 [default: 5].
     ";
 
     #[derive(RustcDecodable, Debug)]
     struct Args {
         arg_source: String,
         arg_dest: Option<String>,
+        cmd_verify: bool,
+        cmd_pictures: bool,
+        cmd_convert: bool,
+        flag_export: Option<String>,
+        flag_level: u8,
         flag_read: bool,
         flag_write: bool,
         flag_help: bool,
@@ -36,34 +396,116 @@ fn main() {
 
     let args: Args = Docopt::new(USAGE).unwrap().decode().unwrap_or_else(|e| e.exit());
 
+    if args.cmd_verify {
+        let mut file = io::BufReader::new(File::open(&args.arg_source).unwrap_or_else(|e| fail(SurfboardError::Io(e))));
+        verify_flac(&mut file);
+        return;
+    }
+
+    if args.cmd_pictures {
+        let file = io::BufReader::new(File::open(&args.arg_source).unwrap_or_else(|e| fail(SurfboardError::Io(e))));
+        flac_pictures(file, args.flag_export.as_ref().map(Path::new));
+        return;
+    }
+
+    if args.cmd_convert {
+        if let Err(e) = convert(&args.arg_source, args.arg_dest.as_ref().unwrap(), args.flag_level) {
+            fail(e);
+        }
+        return;
+    }
+
     if args.flag_write && args.arg_dest.is_some() {
-        let mut data_file = File::open(&args.arg_source).unwrap();
+        let mut data_file = File::open(&args.arg_source).unwrap_or_else(|e| fail(SurfboardError::Io(e)));
 
         let mut data = Vec::new();
-        data_file.read_to_end(&mut data).unwrap();
+        data_file.read_to_end(&mut data).unwrap_or_else(|e| fail(SurfboardError::Io(e)));
 
-        let mut wav_file = File::create(&args.arg_dest.unwrap()).unwrap();
+        let mut wav_file = File::create(&args.arg_dest.unwrap()).unwrap_or_else(|e| fail(SurfboardError::Io(e)));
 
-        Wave::write(&mut wav_file, 1, 44100, 8, data);
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Int(8));
+        if let Err(e) = writer.write(&mut wav_file, &data) {
+            fail(e);
+        }
     }
 
     if args.flag_read || args.flag_write {
-        let mut read_test = File::open(&args.arg_source).unwrap();
+        let mut read_test = io::BufReader::new(File::open(&args.arg_source).unwrap_or_else(|e| fail(SurfboardError::Io(e))));
 
-        let file_id = read_test.read_u32::<LittleEndian>().unwrap();
+        let file_id = read_magic(&mut read_test).unwrap_or_else(|e| fail(SurfboardError::Io(e)));
 
-        let mut t = vec![];
+        println!("file id: {}", String::from_utf8_lossy(&file_id));
 
-        t.write_u32::<LittleEndian>(file_id).unwrap();
-        let file_id = String::from_utf8(t).unwrap();
+        let result = match &file_id {
+            b"RIFF" => Wave::parse(&mut read_test).map(|wave| print_wave(&wave)),
+            b"fLaC" => Flac::parse(&mut read_test).map(|flac| print_flac(&flac)),
+            _ => Err(SurfboardError::BadMagic {
+                offset: 0,
+                expected: "RIFF or fLaC",
+                found: String::from_utf8_lossy(&file_id).into_owned(),
+            }),
+        };
 
-        println!("file id: {}", file_id);
+        if let Err(e) = result {
+            fail(e);
+        }
+    }
+}
 
-        match &*file_id {
-            "RIFF" => { Wave::parse(&mut read_test); },
-            "fLaC" => { Flac::parse(&mut read_test); },
-            _ => panic!("Unrecognized file type"),
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use surfboard::vorbis::VorbisComments;
+    use surfboard::wave::Info;
+    use super::{comments_to_info, info_to_comments};
+
+    fn info(tags: &[(&str, &str)]) -> Info {
+        let mut data = HashMap::new();
+        for &(id, text) in tags.iter() {
+            data.insert(id.to_string(), text.to_string());
         }
+        Info { data: data }
+    }
+
+    #[test]
+    fn maps_info_tags_to_vorbis_comments() {
+        let mut comments = VorbisComments::new("test");
+        let tags = info(&[("INAM", "Title"), ("IART", "Artist"), ("ITRK", "3"), ("ISFT", "Encoder"), ("IKEY", "ignored")]);
+        info_to_comments(&tags, &mut comments).unwrap();
+
+        assert_eq!(comments.len(), 4);
+        assert_eq!(comments.title(), Some("Title"));
+        assert_eq!(comments.artists(), vec!["Artist"]);
+        assert_eq!(comments.track_number(), Some(3));
+        assert_eq!(comments.get("ENCODER"), Some("Encoder"));
+        assert_eq!(comments.vendor, "test");
+    }
+
+    #[test]
+    fn maps_vorbis_comments_to_info_tags() {
+        let mut comments = VorbisComments::new("test");
+        comments.add("title", "Title").unwrap();
+        comments.add("ARTIST", "A").unwrap();
+        comments.add("Artist", "B").unwrap();
+        comments.add("DATE", "2015").unwrap();
+        comments.add("REPLAYGAIN_TRACK_GAIN", "-6.48 dB").unwrap();
+
+        let info = comments_to_info(&comments);
+        assert_eq!(info.data.len(), 3);
+        assert_eq!(info.data["INAM"], "Title");
+        assert_eq!(info.data["IART"], "A; B");
+        assert_eq!(info.data["ICRD"], "2015");
+
+        assert!(comments_to_info(&VorbisComments::new("test")).data.is_empty());
+    }
 
+    #[test]
+    fn round_trips_every_mapped_tag() {
+        let tags = info(&[("INAM", "a"), ("IART", "b"), ("IPRD", "c"), ("ICRD", "d"), ("IGNR", "e"),
+                          ("ITRK", "f"), ("ICMT", "g"), ("ICOP", "h"), ("ISFT", "i")]);
+        let mut comments = VorbisComments::new("test");
+        info_to_comments(&tags, &mut comments).unwrap();
+        assert_eq!(comments.len(), 9);
+        assert_eq!(comments_to_info(&comments).data, tags.data);
     }
 }
@@ -0,0 +1,358 @@
+// Editing FLAC metadata without touching the audio frames.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use error::{SurfboardError, Result, tell};
+use flac::{Block, BlockName, BlockType, FlacReader, Picture};
+use vorbis::VorbisComments;
+
+/// Padding left after the metadata when the file has to be rewritten, so the
+/// next few edits fit in place.
+const REWRITE_PADDING: u64 = 8192;
+
+/// Longest payload a metadata block header can give.
+const MAX_BLOCK_LENGTH: u64 = (1 << 24) - 1;
+
+/// The metadata blocks of a FLAC file, open for editing.
+///
+/// Changes are only made in memory until `save`, which writes the blocks
+/// over the old ones when they fit in the space taken by the old blocks and
+/// their padding, and otherwise rewrites the whole file through a temporary
+/// file that replaces the original once complete.
+pub struct FlacMetadata {
+    path: PathBuf,
+    pub stream_info: Block,
+    /// Every block after STREAMINFO except padding.
+    pub blocks: Vec<Block>,
+    /// Where the audio frames start.
+    audio_offset: u64,
+}
+
+impl FlacMetadata {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<FlacMetadata> {
+        let file = io::BufReader::new(try!(File::open(&path)));
+        let reader = try!(FlacReader::new(file));
+        let stream_info = reader.stream_info.clone();
+        let blocks = reader.blocks.clone().unwrap_or(Vec::new());
+        let audio_offset = try!(tell(&mut reader.into_inner()));
+
+        Ok(FlacMetadata {
+            path: path.as_ref().to_path_buf(),
+            stream_info: stream_info,
+            blocks: blocks.into_iter().filter(|b| match b.type_data {
+                Some(BlockType::Padding(_)) => false,
+                _ => true,
+            }).collect(),
+            audio_offset: audio_offset,
+        })
+    }
+
+    /// The Vorbis comments, if the file has any.
+    pub fn comments(&self) -> Option<&VorbisComments> {
+        self.blocks.iter().filter_map(|block| match block.type_data {
+            Some(BlockType::VorbisComment(ref comments)) => Some(comments),
+            _ => None,
+        }).next()
+    }
+
+    /// The Vorbis comments, adding an empty VORBIS_COMMENT block if there is
+    /// none.
+    pub fn comments_mut(&mut self) -> &mut VorbisComments {
+        let position = self.blocks.iter().position(|block| match block.type_data {
+            Some(BlockType::VorbisComment(_)) => true,
+            _ => false,
+        });
+        let index = match position {
+            Some(index) => index,
+            None => {
+                self.blocks.insert(0, Block {
+                    last_meta: false,
+                    block_name: BlockName::VorbisComment,
+                    length: 0,
+                    type_data: Some(BlockType::VorbisComment(VorbisComments::new("surfboard"))),
+                });
+                0
+            },
+        };
+        match self.blocks[index].type_data {
+            Some(BlockType::VorbisComment(ref mut comments)) => comments,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn pictures(&self) -> Vec<&Picture> {
+        self.blocks.iter().filter_map(|block| match block.type_data {
+            Some(BlockType::Picture(ref picture)) => Some(picture),
+            _ => None,
+        }).collect()
+    }
+
+    pub fn add_picture(&mut self, picture: Picture) -> Result<()> {
+        self.blocks.push(try!(Block::new(BlockType::Picture(picture))));
+        Ok(())
+    }
+
+    /// Removes every picture of the given type, such as 3 for the front cover.
+    pub fn remove_pictures(&mut self, picture_type: u32) {
+        self.blocks.retain(|block| match block.type_data {
+            Some(BlockType::Picture(ref picture)) => picture.picture_type != picture_type,
+            _ => true,
+        });
+    }
+
+    /// Writes the metadata back to the file.
+    pub fn save(&mut self) -> Result<()> {
+        // Edited blocks may have changed size
+        let mut blocks = vec![try!(Block::new(try!(block_data(&self.stream_info))))];
+        for block in self.blocks.iter() {
+            blocks.push(try!(Block::new(try!(block_data(block)))));
+        }
+
+        let needed = blocks.iter().fold(4, |n, b| n + 4 + b.length as u64);
+        if needed == self.audio_offset || needed + 4 <= self.audio_offset {
+            // Every block is built before the file is touched, so a failure
+            // leaves it as it was
+            let padding = try!(padding_blocks(self.audio_offset - needed));
+            let mut file = io::BufWriter::new(try!(OpenOptions::new().write(true).open(&self.path)));
+            try!(write_metadata(&mut file, &blocks, &padding));
+            try!(file.flush());
+            return Ok(());
+        }
+
+        let tmp_path = self.tmp_path();
+        match self.rewrite(&tmp_path, &blocks) {
+            Ok(audio_offset) => {
+                try!(fs::rename(&tmp_path, &self.path));
+                self.audio_offset = audio_offset;
+                Ok(())
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(e)
+            },
+        }
+    }
+
+    /// Writes the new metadata and the old audio frames to `tmp_path`,
+    /// returning where the audio starts in the new file.
+    fn rewrite(&self, tmp_path: &Path, blocks: &[Block]) -> Result<u64> {
+        let mut src = try!(File::open(&self.path));
+        try!(src.seek(io::SeekFrom::Start(self.audio_offset)));
+
+        let padding = try!(padding_blocks(4 + REWRITE_PADDING));
+        let file = try!(File::create(tmp_path));
+        let mut dst = io::BufWriter::new(file);
+        try!(write_metadata(&mut dst, blocks, &padding));
+        let audio_offset = blocks.iter().fold(4, |n, b| n + 4 + b.length as u64) + 4 + REWRITE_PADDING;
+        try!(io::copy(&mut src, &mut dst));
+
+        let file = try!(dst.into_inner().map_err(io::Error::from));
+        try!(file.sync_all());
+        // The temporary file replaces the original, so give it the same mode
+        try!(fs::set_permissions(tmp_path, try!(src.metadata()).permissions()));
+        Ok(audio_offset)
+    }
+
+    /// A sibling of the file being edited, so the final rename stays on one
+    /// filesystem.
+    fn tmp_path(&self) -> PathBuf {
+        let name = self.path.file_name().map_or("flac".into(), |n| n.to_string_lossy().into_owned());
+        self.path.with_file_name(format!(".{}.tmp", name))
+    }
+}
+
+fn block_data(block: &Block) -> Result<BlockType> {
+    match block.type_data {
+        Some(ref type_data) => Ok(type_data.clone()),
+        None => Err(SurfboardError::Malformed { offset: 0, what: "metadata block without data" }),
+    }
+}
+
+/// Padding blocks taking up exactly `space` bytes, headers included, which
+/// must be 0 or at least 4. Space past what one block can hold is split
+/// across several.
+fn padding_blocks(space: u64) -> Result<Vec<Block>> {
+    let mut padding = Vec::new();
+    let mut left = space;
+    while left > 0 {
+        let mut length = cmp::min(left - 4, MAX_BLOCK_LENGTH);
+        // Leave room for the next block's header
+        let rest = left - 4 - length;
+        if rest > 0 && rest < 4 {
+            length -= 4;
+        }
+        padding.push(try!(Block::new(BlockType::Padding(length))));
+        left -= 4 + length;
+    }
+    Ok(padding)
+}
+
+/// Writes `fLaC`, the blocks and then the padding blocks.
+fn write_metadata<W: io::Write>(w: &mut W, blocks: &[Block], padding: &[Block]) -> Result<()> {
+    try!(w.write_all(b"fLaC"));
+    let count = blocks.len() + padding.len();
+    for (i, block) in blocks.iter().chain(padding.iter()).enumerate() {
+        try!(block.write(w, i == count - 1));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use flac::{Block, BlockType, Flac, Md5Status, Picture};
+    use flac_encoder::{EncoderConfig, FlacEncoder};
+    use vorbis::VorbisComments;
+    use super::{padding_blocks, FlacMetadata, MAX_BLOCK_LENGTH};
+
+    /// Samples of the stream `fixture` writes, one vector per channel.
+    fn samples() -> Vec<Vec<i32>> {
+        let left = (0..3000).map(|i| (i * 37 % 2000) - 1000).collect();
+        let right = (0..3000).map(|i| (i * 11 % 500) - 250).collect();
+        vec![left, right]
+    }
+
+    /// Writes a stream titled "Original" to a file named after the test,
+    /// with `padding` bytes of padding if any.
+    fn fixture(name: &str, padding: u64) -> PathBuf {
+        let path = env::temp_dir().join(format!("surfboard_metadata_{}.flac", name));
+        let mut comments = VorbisComments::new("test");
+        comments.add("TITLE", "Original").unwrap();
+        let mut blocks = vec![Block::new(BlockType::VorbisComment(comments)).unwrap()];
+        if padding > 0 {
+            blocks.push(Block::new(BlockType::Padding(padding)).unwrap());
+        }
+
+        let channels = samples();
+        let mut interleaved = Vec::new();
+        for i in 0..channels[0].len() {
+            interleaved.push(channels[0][i]);
+            interleaved.push(channels[1][i]);
+        }
+        let file = File::create(&path).unwrap();
+        let mut encoder = FlacEncoder::with_blocks(file, 44100, 2, 16, EncoderConfig::default(), &blocks).unwrap();
+        encoder.write_samples(&interleaved).unwrap();
+        encoder.finish().unwrap();
+        path
+    }
+
+    /// Checks the audio came through an edit untouched.
+    fn assert_audio_unchanged(path: &PathBuf) {
+        let flac = Flac::parse(&mut File::open(path).unwrap()).unwrap();
+        assert_eq!(flac.samples(), samples());
+        assert_eq!(flac.verify(), Md5Status::Match);
+    }
+
+    fn picture(size: usize) -> Picture {
+        Picture {
+            picture_type: 3,
+            mime_type: "image/png".to_string(),
+            description: "cover".to_string(),
+            width: 1,
+            height: 1,
+            depth: 24,
+            colors: 0,
+            data: vec![7; size],
+        }
+    }
+
+    #[test]
+    fn saves_in_place_within_padding() {
+        let path = fixture("in_place", 4096);
+        let length = fs::metadata(&path).unwrap().len();
+
+        let mut metadata = FlacMetadata::open(&path).unwrap();
+        metadata.comments_mut().add("ARTIST", "Someone").unwrap();
+        metadata.add_picture(picture(1000)).unwrap();
+        metadata.save().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+
+        let metadata = FlacMetadata::open(&path).unwrap();
+        assert_eq!(metadata.comments().unwrap().title(), Some("Original"));
+        assert_eq!(metadata.comments().unwrap().artists(), vec!["Someone"]);
+        assert_eq!(metadata.pictures().len(), 1);
+        assert_eq!(metadata.pictures()[0].description, "cover");
+        assert_eq!(metadata.pictures()[0].data, picture(1000).data);
+        assert_audio_unchanged(&path);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rewrites_when_padding_runs_out() {
+        let path = fixture("rewrite", 100);
+        let length = fs::metadata(&path).unwrap().len();
+
+        let mut metadata = FlacMetadata::open(&path).unwrap();
+        metadata.comments_mut().set("TITLE", "Rewritten").unwrap();
+        metadata.add_picture(picture(20000)).unwrap();
+        metadata.save().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > length + 20000);
+        assert!(!metadata.tmp_path().exists());
+
+        let metadata = FlacMetadata::open(&path).unwrap();
+        assert_eq!(metadata.comments().unwrap().title(), Some("Rewritten"));
+        assert_eq!(metadata.pictures().len(), 1);
+        assert_eq!(metadata.pictures()[0].description, "cover");
+        assert_eq!(metadata.pictures()[0].data, picture(20000).data);
+        assert_audio_unchanged(&path);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saves_again_after_a_rewrite() {
+        let path = fixture("save_again", 0);
+        let mut metadata = FlacMetadata::open(&path).unwrap();
+        metadata.add_picture(picture(5000)).unwrap();
+        metadata.save().unwrap();
+        let length = fs::metadata(&path).unwrap().len();
+
+        // The rewrite left padding, so this one fits in place
+        metadata.remove_pictures(3);
+        metadata.comments_mut().add("ALBUM", "Second").unwrap();
+        metadata.save().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+
+        let metadata = FlacMetadata::open(&path).unwrap();
+        assert_eq!(metadata.comments().unwrap().title(), Some("Original"));
+        assert_eq!(metadata.comments().unwrap().album(), Some("Second"));
+        assert!(metadata.pictures().is_empty());
+        assert_audio_unchanged(&path);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_permissions_when_rewriting() {
+        // Without padding any longer comment needs a rewrite
+        let path = fixture("permissions", 0);
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&path, permissions.clone()).unwrap();
+        let length = fs::metadata(&path).unwrap().len();
+
+        let mut metadata = FlacMetadata::open(&path).unwrap();
+        metadata.comments_mut().set("TITLE", "Rewritten").unwrap();
+        metadata.save().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > length);
+        assert!(fs::metadata(&path).unwrap().permissions().readonly());
+        assert_audio_unchanged(&path);
+
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn padding_fills_space_exactly() {
+        let max = 4 + MAX_BLOCK_LENGTH;
+        for &space in [0, 4, 8196, max, max + 1, max + 3, max + 4, 2 * max + 10].iter() {
+            let padding = padding_blocks(space).unwrap();
+            assert_eq!(padding.iter().fold(0, |n, b| n + 4 + b.length as u64), space);
+            assert!(padding.iter().all(|b| b.length as u64 <= MAX_BLOCK_LENGTH));
+        }
+    }
+}
@@ -0,0 +1,245 @@
+// Walking the chunks of a RIFF file, such as a WAV file.
+
+use std::io;
+use byteorder::{LittleEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use error::{SurfboardError, Result, ReadContext, tell, expect_id, read_bytes};
+
+/// Size given to a chunk whose length is not known, as when streaming to a
+/// pipe. Such a chunk runs to the end of the file.
+pub const UNKNOWN_SIZE: u32 = 0xFFFFFFFF;
+
+/// The id and size of a chunk, and where it starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkHeader {
+    pub id: [u8; 4],
+    /// Payload size, without the header or pad byte.
+    pub size: u32,
+    /// Offset of the chunk id.
+    pub offset: u64,
+}
+
+impl ChunkHeader {
+    /// Offset of the payload.
+    pub fn data_offset(&self) -> u64 {
+        self.offset + 8
+    }
+
+    /// Offset just past the chunk and its pad byte, or `None` for a chunk of
+    /// unknown size.
+    pub fn end(&self) -> Option<u64> {
+        if self.size == UNKNOWN_SIZE {
+            None
+        } else {
+            Some(self.data_offset() + self.size as u64 + self.size as u64 % 2)
+        }
+    }
+}
+
+/// Iterates over the chunks inside a RIFF form, in file order.
+///
+/// Each chunk leaves `r` at its id, so it can be parsed in place or skipped.
+/// Whatever was read of one chunk, the next is found from its size and pad
+/// byte. Iteration ends at the end of the form, the end of the file or after
+/// a chunk of unknown size.
+pub struct Chunks<R> {
+    r: R,
+    /// Where the RIFF size says the form ends, if it says.
+    end: Option<u64>,
+    /// Offset of the next chunk id, or `None` once there are no more.
+    next: Option<u64>,
+}
+
+impl<R: io::Read + io::Seek> Chunks<R> {
+    /// Reads a `RIFF` header for form type `form`, such as `WAVE`, returning
+    /// the chunks and the RIFF chunk size.
+    pub fn new(mut r: R, form: &'static str) -> Result<(Chunks<R>, u32)> {
+        let offset = try!(tell(&mut r));
+        try!(expect_id(&mut r, "RIFF", offset));
+        let chunk_size = try!(r.read_u32::<LittleEndian>().at("RIFF header", offset));
+        try!(expect_id(&mut r, form, offset + 8));
+
+        let chunks = Chunks {
+            r: r,
+            end: if chunk_size == UNKNOWN_SIZE { None } else { Some(offset + 8 + chunk_size as u64) },
+            next: Some(offset + 12),
+        };
+        Ok((chunks, chunk_size))
+    }
+
+    /// Reads the payload of `header`, which must hold all of it.
+    pub fn read_data(&mut self, header: &ChunkHeader) -> Result<Vec<u8>> {
+        try!(self.r.seek(io::SeekFrom::Start(header.data_offset())));
+        if header.size == UNKNOWN_SIZE {
+            let mut data = Vec::new();
+            try!(self.r.read_to_end(&mut data));
+            return Ok(data);
+        }
+        read_bytes(&mut self.r, header.size, "chunk", header.offset)
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.r
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    fn next_header(&mut self) -> Result<Option<ChunkHeader>> {
+        let offset = match self.next {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        if self.end.map_or(false, |end| offset + 8 > end) {
+            self.next = None;
+            return Ok(None);
+        }
+
+        try!(self.r.seek(io::SeekFrom::Start(offset)));
+        let mut header = [0; 8];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.r.read(&mut header[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(SurfboardError::Io(e)),
+            }
+        }
+        // Files often end before their RIFF size says
+        if filled == 0 {
+            self.next = None;
+            return Ok(None);
+        }
+        if filled < header.len() {
+            self.next = None;
+            return Err(SurfboardError::Truncated { offset: offset, what: "chunk header" });
+        }
+
+        let chunk = ChunkHeader {
+            id: [header[0], header[1], header[2], header[3]],
+            size: LittleEndian::read_u32(&header[4..]),
+            offset: offset,
+        };
+        self.next = chunk.end();
+        try!(self.r.seek(io::SeekFrom::Start(offset)));
+        Ok(Some(chunk))
+    }
+}
+
+impl<R: io::Read + io::Seek> Iterator for Chunks<R> {
+    type Item = Result<ChunkHeader>;
+
+    fn next(&mut self) -> Option<Result<ChunkHeader>> {
+        match self.next_header() {
+            Ok(Some(header)) => Some(Ok(header)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// A chunk kept as raw bytes, such as `fact`, `bext` or `id3 `, so it can be
+/// written back unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawChunk {
+    pub id: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl RawChunk {
+    pub fn parse<R: io::Read + io::Seek>(chunks: &mut Chunks<R>, header: &ChunkHeader) -> Result<RawChunk> {
+        Ok(RawChunk {
+            id: header.id,
+            data: try!(chunks.read_data(header)),
+        })
+    }
+
+    /// Bytes taken in the file, including the header and pad byte.
+    pub fn size(&self) -> u64 {
+        8 + self.data.len() as u64 + self.data.len() as u64 % 2
+    }
+
+    pub fn write<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(w.write_all(&self.id));
+        try!(w.write_u32::<LittleEndian>(self.data.len() as u32));
+        try!(w.write_all(&self.data));
+        // Chunks are word aligned
+        if self.data.len() % 2 != 0 {
+            try!(w.write_u8(0));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::{Chunks, RawChunk, UNKNOWN_SIZE};
+
+    fn file(chunks: &[RawChunk]) -> Vec<u8> {
+        let mut body = b"WAVE".to_vec();
+        for chunk in chunks {
+            chunk.write(&mut body).unwrap();
+        }
+        let mut bytes = b"RIFF".to_vec();
+        let len = body.len() as u32;
+        bytes.extend([len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8].iter().cloned());
+        bytes.extend(body);
+        bytes
+    }
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> RawChunk {
+        RawChunk { id: *id, data: data.to_vec() }
+    }
+
+    #[test]
+    fn walks_chunks_over_pad_bytes() {
+        let bytes = file(&[chunk(b"JUNK", b"abc"), chunk(b"fmt ", &[0; 16]), chunk(b"id3 ", b"x")]);
+        let (mut chunks, chunk_size) = Chunks::new(Cursor::new(bytes.clone()), "WAVE").unwrap();
+        assert_eq!(chunk_size as usize, bytes.len() - 8);
+
+        let headers: Vec<_> = chunks.by_ref().map(|h| h.unwrap()).collect();
+        let ids: Vec<_> = headers.iter().map(|h| h.id).collect();
+        assert_eq!(ids, vec![*b"JUNK", *b"fmt ", *b"id3 "]);
+        assert_eq!(headers[1].offset, 12 + 8 + 4);
+        assert_eq!(headers[1].size, 16);
+        assert_eq!(chunks.read_data(&headers[0]).unwrap(), b"abc".to_vec());
+        assert_eq!(RawChunk::parse(&mut chunks, &headers[2]).unwrap(), chunk(b"id3 ", b"x"));
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_file_or_form() {
+        // A RIFF size past the end of the file
+        let mut bytes = file(&[chunk(b"JUNK", b"ab")]);
+        bytes[4] += 100;
+        let (chunks, _) = Chunks::new(Cursor::new(bytes), "WAVE").unwrap();
+        assert_eq!(chunks.count(), 1);
+
+        // Data after the RIFF form is not part of it
+        let mut bytes = file(&[chunk(b"JUNK", b"ab")]);
+        bytes.extend(b"junk\x00\x00\x00\x00".iter().cloned());
+        let (chunks, _) = Chunks::new(Cursor::new(bytes), "WAVE").unwrap();
+        assert_eq!(chunks.count(), 1);
+
+        // A chunk of unknown size runs to the end
+        let mut bytes = file(&[chunk(b"data", b"ab"), chunk(b"JUNK", b"cd")]);
+        for b in bytes[16..20].iter_mut() {
+            *b = 0xFF;
+        }
+        let (mut chunks, _) = Chunks::new(Cursor::new(bytes), "WAVE").unwrap();
+        let header = chunks.next().unwrap().unwrap();
+        assert_eq!(header.size, UNKNOWN_SIZE);
+        assert_eq!(chunks.read_data(&header).unwrap(), b"abJUNK\x02\x00\x00\x00cd".to_vec());
+        assert!(chunks.next().is_none());
+
+        // A header cut short
+        let mut bytes = file(&[chunk(b"JUNK", b"ab")]);
+        bytes.extend(b"jun".iter().cloned());
+        bytes[4] += 8;
+        let (mut chunks, _) = Chunks::new(Cursor::new(bytes), "WAVE").unwrap();
+        assert!(chunks.next().unwrap().is_ok());
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+    }
+}
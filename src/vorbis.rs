@@ -0,0 +1,304 @@
+// Vorbis comments, as stored in FLAC VORBIS_COMMENT blocks.
+// Based on https://xiph.org/vorbis/doc/v-comment.html
+
+use std::io;
+use std::slice;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use error::{SurfboardError, Result, ReadContext, tell, read_string};
+
+/// A vendor string and a list of `NAME=value` fields.
+///
+/// Field names are case insensitive and may repeat, as in one ARTIST field per
+/// artist. Fields keep the order and spelling they were read or added with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VorbisComments {
+    pub vendor: String,
+    fields: Vec<(String, String)>,
+    /// Entries read without a valid `NAME=`, written back as they were.
+    raw: Vec<String>,
+}
+
+/// Field names are printable ASCII, 0x20 through 0x7D, without `=`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b >= 0x20 && b <= 0x7D && b != b'=')
+}
+
+fn same_name(a: &str, b: &str) -> bool {
+    a.to_uppercase() == b.to_uppercase()
+}
+
+impl VorbisComments {
+    pub fn new(vendor: &str) -> VorbisComments {
+        VorbisComments {
+            vendor: vendor.to_string(),
+            fields: Vec::new(),
+            raw: Vec::new(),
+        }
+    }
+
+    pub fn parse<R: io::Read + io::Seek>(r: &mut R) -> Result<VorbisComments> {
+        let offset = try!(tell(r));
+        let vendor_length = try!(r.read_u32::<LittleEndian>().at("VORBIS_COMMENT block", offset));
+        let vendor = try!(read_string(r, vendor_length, "VORBIS_COMMENT block", offset));
+
+        let num_fields = try!(r.read_u32::<LittleEndian>().at("VORBIS_COMMENT block", offset));
+
+        let mut fields = Vec::new();
+        let mut raw = Vec::new();
+        for _ in 0..num_fields {
+            let field_length = try!(r.read_u32::<LittleEndian>().at("VORBIS_COMMENT block", offset));
+            let field = try!(read_string(r, field_length, "VORBIS_COMMENT block", offset));
+
+            // Entries without a valid name are kept aside rather than failing
+            // the file; only `add` and `set` enforce the naming rules
+            match field.find('=') {
+                Some(i) if is_valid_name(&field[..i]) => fields.push((field[..i].to_string(), field[i + 1..].to_string())),
+                _ => raw.push(field),
+            }
+        }
+
+        Ok(VorbisComments {
+            vendor: vendor,
+            fields: fields,
+            raw: raw,
+        })
+    }
+
+    pub fn write<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(w.write_u32::<LittleEndian>(self.vendor.len() as u32));
+        try!(w.write_all(self.vendor.as_bytes()));
+        try!(w.write_u32::<LittleEndian>((self.fields.len() + self.raw.len()) as u32));
+        for &(ref name, ref value) in self.fields.iter() {
+            try!(w.write_u32::<LittleEndian>((name.len() + 1 + value.len()) as u32));
+            try!(w.write_all(name.as_bytes()));
+            try!(w.write_all(b"="));
+            try!(w.write_all(value.as_bytes()));
+        }
+        for entry in self.raw.iter() {
+            try!(w.write_u32::<LittleEndian>(entry.len() as u32));
+            try!(w.write_all(entry.as_bytes()));
+        }
+        Ok(())
+    }
+
+    /// Entries read without a valid `NAME=`, which are not fields but are
+    /// kept so they can be written back.
+    pub fn raw_entries(&self) -> &[String] {
+        &self.raw
+    }
+
+    /// Every field as a `(name, value)` pair.
+    pub fn iter<'a>(&'a self) -> slice::Iter<'a, (String, String)> {
+        self.fields.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// The first value of field `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields.iter().find(|f| same_name(&f.0, name)).map(|f| &*f.1)
+    }
+
+    /// Every value of field `name`, in order.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.fields.iter().filter(|f| same_name(&f.0, name)).map(|f| &*f.1).collect()
+    }
+
+    /// Adds a value to field `name`, keeping any it already has.
+    pub fn add(&mut self, name: &str, value: &str) -> Result<()> {
+        if !is_valid_name(name) {
+            return Err(SurfboardError::Malformed { offset: 0, what: "Vorbis comment field name" });
+        }
+        self.fields.push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Replaces every value of field `name` with `value`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        if !is_valid_name(name) {
+            return Err(SurfboardError::Malformed { offset: 0, what: "Vorbis comment field name" });
+        }
+        self.remove(name);
+        self.add(name, value)
+    }
+
+    /// Removes every value of field `name`.
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|f| !same_name(&f.0, name));
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.get("TITLE")
+    }
+
+    pub fn artists(&self) -> Vec<&str> {
+        self.get_all("ARTIST")
+    }
+
+    pub fn album(&self) -> Option<&str> {
+        self.get("ALBUM")
+    }
+
+    pub fn date(&self) -> Option<&str> {
+        self.get("DATE")
+    }
+
+    pub fn genres(&self) -> Vec<&str> {
+        self.get_all("GENRE")
+    }
+
+    /// TRACKNUMBER, also accepting the `3/12` form.
+    pub fn track_number(&self) -> Option<u32> {
+        self.get("TRACKNUMBER").and_then(|v| v.split('/').next()).and_then(|v| v.trim().parse().ok())
+    }
+
+    /// TRACKTOTAL, falling back to the `3/12` form of TRACKNUMBER.
+    pub fn track_total(&self) -> Option<u32> {
+        self.get("TRACKTOTAL").or_else(|| self.get("TOTALTRACKS"))
+            .or_else(|| self.get("TRACKNUMBER").and_then(|v| v.split('/').nth(1)))
+            .and_then(|v| v.trim().parse().ok())
+    }
+
+    pub fn disc_number(&self) -> Option<u32> {
+        self.get("DISCNUMBER").and_then(|v| v.split('/').next()).and_then(|v| v.trim().parse().ok())
+    }
+
+    /// REPLAYGAIN_TRACK_GAIN in dB.
+    pub fn replaygain_track_gain(&self) -> Option<f64> {
+        self.float("REPLAYGAIN_TRACK_GAIN")
+    }
+
+    pub fn replaygain_track_peak(&self) -> Option<f64> {
+        self.float("REPLAYGAIN_TRACK_PEAK")
+    }
+
+    /// REPLAYGAIN_ALBUM_GAIN in dB.
+    pub fn replaygain_album_gain(&self) -> Option<f64> {
+        self.float("REPLAYGAIN_ALBUM_GAIN")
+    }
+
+    pub fn replaygain_album_peak(&self) -> Option<f64> {
+        self.float("REPLAYGAIN_ALBUM_PEAK")
+    }
+
+    /// Parses values such as `-6.48 dB` or `0.988525`.
+    fn float(&self, name: &str) -> Option<f64> {
+        self.get(name).and_then(|v| {
+            let v = v.trim();
+            let v = if v.to_uppercase().ends_with("DB") { v[..v.len() - 2].trim() } else { v };
+            v.parse().ok()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use byteorder::{LittleEndian, WriteBytesExt};
+    use super::VorbisComments;
+
+    /// A VORBIS_COMMENT block body holding `entries`.
+    fn block(vendor: &str, entries: &[&str]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LittleEndian>(vendor.len() as u32).unwrap();
+        bytes.extend(vendor.bytes());
+        bytes.write_u32::<LittleEndian>(entries.len() as u32).unwrap();
+        for entry in entries.iter() {
+            bytes.write_u32::<LittleEndian>(entry.len() as u32).unwrap();
+            bytes.extend(entry.bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn keeps_entries_without_a_valid_name() {
+        let bytes = block("vendor", &["TITLE=ok", "NOEQUALS", "BAD~NAME=x", "=empty"]);
+        let comments = VorbisComments::parse(&mut Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(comments.vendor, "vendor");
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments.title(), Some("ok"));
+        assert_eq!(comments.raw_entries(), &["NOEQUALS".to_string(), "BAD~NAME=x".to_string(), "=empty".to_string()]);
+
+        let mut written = Vec::new();
+        comments.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+    #[test]
+    fn looks_up_fields_case_insensitively() {
+        let mut comments = VorbisComments::new("test");
+        comments.add("Title", "One").unwrap();
+        comments.add("artist", "A").unwrap();
+        comments.add("ARTIST", "B").unwrap();
+        assert_eq!(comments.get("TITLE"), Some("One"));
+        assert_eq!(comments.get("title"), Some("One"));
+        assert_eq!(comments.get_all("Artist"), vec!["A", "B"]);
+        assert_eq!(comments.artists(), vec!["A", "B"]);
+        assert_eq!(comments.get("ALBUM"), None);
+
+        // Set replaces every spelling, keeping the new one
+        comments.set("ARTIST", "C").unwrap();
+        assert_eq!(comments.artists(), vec!["C"]);
+        assert_eq!(comments.iter().filter(|f| f.0 == "ARTIST").count(), 1);
+        comments.remove("TITLE");
+        assert_eq!(comments.title(), None);
+        assert_eq!(comments.len(), 1);
+    }
+
+    #[test]
+    fn round_trips_multi_valued_fields_in_order() {
+        let mut comments = VorbisComments::new("vendor ✓");
+        comments.add("ARTIST", "First").unwrap();
+        comments.add("TITLE", "Song").unwrap();
+        comments.add("Artist", "Second").unwrap();
+        let mut bytes = Vec::new();
+        comments.write(&mut bytes).unwrap();
+        assert_eq!(bytes, block("vendor ✓", &["ARTIST=First", "TITLE=Song", "Artist=Second"]));
+
+        let parsed = VorbisComments::parse(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(parsed, comments);
+        assert_eq!(parsed.artists(), vec!["First", "Second"]);
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let mut comments = VorbisComments::new("test");
+        for name in ["", "A=B", "TAB\t", "TILDE~", "\u{e9}"].iter() {
+            assert!(comments.add(name, "x").is_err(), "{:?}", name);
+            assert!(comments.set(name, "x").is_err(), "{:?}", name);
+        }
+        assert!(comments.is_empty());
+        comments.add(" !\"#<}", "x").unwrap();
+        assert_eq!(comments.len(), 1);
+    }
+
+    #[test]
+    fn parses_track_numbers_and_replaygain() {
+        let mut comments = VorbisComments::new("test");
+        comments.add("TRACKNUMBER", "3/12").unwrap();
+        comments.add("DISCNUMBER", " 2 / 3").unwrap();
+        comments.add("REPLAYGAIN_TRACK_GAIN", "-6.48 dB").unwrap();
+        comments.add("REPLAYGAIN_TRACK_PEAK", "0.988525").unwrap();
+        comments.add("REPLAYGAIN_ALBUM_GAIN", "1.5 db").unwrap();
+        comments.add("REPLAYGAIN_ALBUM_PEAK", "loud").unwrap();
+        assert_eq!(comments.track_number(), Some(3));
+        assert_eq!(comments.track_total(), Some(12));
+        assert_eq!(comments.disc_number(), Some(2));
+        assert_eq!(comments.replaygain_track_gain(), Some(-6.48));
+        assert_eq!(comments.replaygain_track_peak(), Some(0.988525));
+        assert_eq!(comments.replaygain_album_gain(), Some(1.5));
+        assert_eq!(comments.replaygain_album_peak(), None);
+
+        // A separate total wins over the `3/12` form
+        comments.add("TRACKTOTAL", "10").unwrap();
+        assert_eq!(comments.track_total(), Some(10));
+        comments.set("TRACKNUMBER", "4").unwrap();
+        assert_eq!(comments.track_number(), Some(4));
+        assert_eq!(comments.track_total(), Some(10));
+    }
+}
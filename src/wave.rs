@@ -1,64 +1,228 @@
-#![allow(dead_code)]
-
-use std::str;
 use std::io;
+use std::marker::PhantomData;
+use std::mem;
 use std::io::prelude::*;
 use std::collections::HashMap;
 use byteorder::{LittleEndian, BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use error::{SurfboardError, Result, ReadContext, tell, read_id, expect_id};
+use riff::{Chunks, RawChunk};
+pub use riff::UNKNOWN_SIZE;
 
-struct Fmt {
-    id: u32,
-    size: u32,
-    audio_fmt: u16,
-    num_channels: u16,
-    sample_rate: u32,
-    byte_rate: u32,
-    block_align: u16,
-    bits_per_sample: u16,
+/// `audio_fmt` of integer PCM.
+pub const WAVE_FORMAT_PCM: u16 = 1;
+/// `audio_fmt` of IEEE float.
+pub const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+/// `audio_fmt` of a fmt chunk whose real format is in its `Extensible` part.
+pub const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// The tail shared by the sub format GUIDs of the basic formats, after
+/// their two byte format code and two zero bytes.
+const SUB_FORMAT_TAIL: [u8; 12] = [0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
+/// A speaker position in a channel mask. Channels are stored in the order
+/// listed here, for whichever speakers the mask holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speaker {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    FrontLeftOfCenter,
+    FrontRightOfCenter,
+    BackCenter,
+    SideLeft,
+    SideRight,
+    TopCenter,
+    TopFrontLeft,
+    TopFrontCenter,
+    TopFrontRight,
+    TopBackLeft,
+    TopBackCenter,
+    TopBackRight,
+}
+
+const SPEAKERS: [Speaker; 18] = [
+    Speaker::FrontLeft, Speaker::FrontRight, Speaker::FrontCenter, Speaker::LowFrequency,
+    Speaker::BackLeft, Speaker::BackRight, Speaker::FrontLeftOfCenter, Speaker::FrontRightOfCenter,
+    Speaker::BackCenter, Speaker::SideLeft, Speaker::SideRight, Speaker::TopCenter,
+    Speaker::TopFrontLeft, Speaker::TopFrontCenter, Speaker::TopFrontRight,
+    Speaker::TopBackLeft, Speaker::TopBackCenter, Speaker::TopBackRight,
+];
+
+impl Speaker {
+    /// This speaker's bit in a channel mask.
+    pub fn bit(&self) -> u32 {
+        1 << SPEAKERS.iter().position(|s| s == self).unwrap()
+    }
+}
+
+/// Which speakers the channels of an extensible file are meant for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelMask(pub u32);
+
+impl ChannelMask {
+    pub fn from_speakers(speakers: &[Speaker]) -> ChannelMask {
+        ChannelMask(speakers.iter().fold(0, |mask, s| mask | s.bit()))
+    }
+
+    /// The usual layout for `num_channels` channels, the same one FLAC
+    /// assumes: mono, stereo, 3.0, quad, 5.0, 5.1, 6.1 and 7.1. Other counts
+    /// have no speakers assigned.
+    pub fn for_channels(num_channels: u16) -> ChannelMask {
+        use self::Speaker::*;
+        ChannelMask::from_speakers(match num_channels {
+            1 => &[FrontCenter],
+            2 => &[FrontLeft, FrontRight],
+            3 => &[FrontLeft, FrontRight, FrontCenter],
+            4 => &[FrontLeft, FrontRight, BackLeft, BackRight],
+            5 => &[FrontLeft, FrontRight, FrontCenter, BackLeft, BackRight],
+            6 => &[FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight],
+            7 => &[FrontLeft, FrontRight, FrontCenter, LowFrequency, BackCenter, SideLeft, SideRight],
+            8 => &[FrontLeft, FrontRight, FrontCenter, LowFrequency, BackLeft, BackRight, SideLeft, SideRight],
+            _ => &[],
+        })
+    }
+
+    /// The speakers in the mask, in channel order.
+    pub fn speakers(&self) -> Vec<Speaker> {
+        SPEAKERS.iter().cloned().filter(|s| self.0 & s.bit() != 0).collect()
+    }
+}
+
+/// The extra fields of a WAVE_FORMAT_EXTENSIBLE fmt chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extensible {
+    /// Bits of each sample that hold audio, at most `bits_per_sample`.
+    pub valid_bits_per_sample: u16,
+    pub channel_mask: ChannelMask,
+    pub sub_format: [u8; 16],
+}
+
+impl Extensible {
+    /// The sub format GUID of a basic format, such as `WAVE_FORMAT_PCM`.
+    pub fn sub_format_for(audio_fmt: u16) -> [u8; 16] {
+        let mut guid = [0; 16];
+        guid[0] = audio_fmt as u8;
+        guid[1] = (audio_fmt >> 8) as u8;
+        for (dst, src) in guid[4..].iter_mut().zip(SUB_FORMAT_TAIL.iter()) {
+            *dst = *src;
+        }
+        guid
+    }
+
+    /// The basic format the sub format GUID stands for, if it is one.
+    pub fn sub_format_code(&self) -> Option<u16> {
+        if self.sub_format[2..4] == [0, 0] && self.sub_format[4..] == SUB_FORMAT_TAIL {
+            Some(LittleEndian::read_u16(&self.sub_format[..2]))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Fmt {
+    pub id: u32,
+    pub size: u32,
+    pub audio_fmt: u16,
+    pub num_channels: u16,
+    pub sample_rate: u32,
+    pub byte_rate: u32,
+    pub block_align: u16,
+    /// Bits each sample takes. With `extensible`, only the valid bits of
+    /// these hold audio.
+    pub bits_per_sample: u16,
+    pub extensible: Option<Extensible>,
 }
 
 impl Fmt {
-    fn write<W: io::Write>(w: &mut W, num_channels: u16, sample_rate: u32, bits_per_sample: u16) {
-        let id = BigEndian::read_u32(b" fmt");
-        let size = 16; //PCM format size
-        let audio_fmt = 1; //Linear Quantization
-        let byte_rate = (sample_rate * (num_channels as u32) * (bits_per_sample as u32)) / 8;
-        let block_align = (num_channels * bits_per_sample) / 8;
-
-        w.write_u32::<BigEndian>(id).unwrap();
-        w.write_u32::<LittleEndian>(size).unwrap();
-        w.write_u16::<LittleEndian>(audio_fmt).unwrap();
-        w.write_u16::<LittleEndian>(num_channels).unwrap();
-        w.write_u32::<LittleEndian>(sample_rate).unwrap();
-        w.write_u32::<LittleEndian>(byte_rate).unwrap();
-        w.write_u16::<LittleEndian>(block_align).unwrap();
-        w.write_u16::<LittleEndian>(bits_per_sample).unwrap();
-    }
-
-    fn parse<R: io::Read>(r: &mut R) -> Fmt {
-        let id = r.read_u32::<LittleEndian>().unwrap();
-        let size = r.read_u32::<LittleEndian>().unwrap();
-        let audio_fmt = r.read_u16::<LittleEndian>().unwrap();
-        let num_channels = r.read_u16::<LittleEndian>().unwrap();
-        let sample_rate = r.read_u32::<LittleEndian>().unwrap();
-        let byte_rate = r.read_u32::<LittleEndian>().unwrap();
-        let block_align = r.read_u16::<LittleEndian>().unwrap();
-        let bits_per_sample = r.read_u16::<LittleEndian>().unwrap();
-
-        let mut t = vec![];
-        t.write_u32::<LittleEndian>(id).unwrap();
-        println!("\nfmt id: {}", str::from_utf8(&t).unwrap());
-        t.clear();
-
-        println!("fmt size: {}", size);
-        println!("audio format: {}", audio_fmt);
-        println!("number of channels: {}", num_channels);
-        println!("sample rate: {} Hz", sample_rate);
-        println!("byte rate: {}", byte_rate);
-        println!("block alignment: {}", block_align);
-        println!("bits per sample: {}", bits_per_sample);
+    pub fn write<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(w.write_all(b"fmt "));
+        try!(w.write_u32::<LittleEndian>(self.size));
+        try!(w.write_u16::<LittleEndian>(self.audio_fmt));
+        try!(w.write_u16::<LittleEndian>(self.num_channels));
+        try!(w.write_u32::<LittleEndian>(self.sample_rate));
+        try!(w.write_u32::<LittleEndian>(self.byte_rate));
+        try!(w.write_u16::<LittleEndian>(self.block_align));
+        try!(w.write_u16::<LittleEndian>(self.bits_per_sample));
+        if let Some(ref ext) = self.extensible {
+            try!(w.write_u16::<LittleEndian>(22)); // cbSize
+            try!(w.write_u16::<LittleEndian>(ext.valid_bits_per_sample));
+            try!(w.write_u32::<LittleEndian>(ext.channel_mask.0));
+            try!(w.write_all(&ext.sub_format));
+        }
+        Ok(())
+    }
 
-        Fmt {
+    /// The sample format, if it is one this crate can read. The bit depth of
+    /// extensible integer formats is their valid bits.
+    pub fn sample_format(&self) -> Option<SampleFormat> {
+        let (audio_fmt, bits) = match self.extensible {
+            Some(ref ext) => match ext.sub_format_code() {
+                Some(code) if ext.valid_bits_per_sample <= self.bits_per_sample => (code, ext.valid_bits_per_sample),
+                _ => return None,
+            },
+            None => (self.audio_fmt, self.bits_per_sample),
+        };
+        // Samples are decoded from containers of at most 32 bits for integers,
+        // and of exactly their own size for floats
+        match (audio_fmt, bits, self.bits_per_sample) {
+            (WAVE_FORMAT_PCM, 1...32, 1...32) => Some(SampleFormat::Int(bits)),
+            (WAVE_FORMAT_IEEE_FLOAT, 32, 32) | (WAVE_FORMAT_IEEE_FLOAT, 64, 64) => Some(SampleFormat::Float(bits)),
+            _ => None,
+        }
+    }
+
+    /// The speakers the channels are for, from the channel mask of an
+    /// extensible fmt chunk or else the usual layout for the channel count.
+    pub fn channel_mask(&self) -> ChannelMask {
+        match self.extensible {
+            Some(ref ext) => ext.channel_mask,
+            None => ChannelMask::for_channels(self.num_channels),
+        }
+    }
+
+    pub fn parse<R: io::Read + io::Seek>(r: &mut R) -> Result<Fmt> {
+        let offset = try!(tell(r));
+        try!(expect_id(r, "fmt ", offset));
+        let id = LittleEndian::read_u32(b"fmt ");
+        let size = try!(r.read_u32::<LittleEndian>().at("fmt chunk", offset));
+        let audio_fmt = try!(r.read_u16::<LittleEndian>().at("fmt chunk", offset));
+        let num_channels = try!(r.read_u16::<LittleEndian>().at("fmt chunk", offset));
+        let sample_rate = try!(r.read_u32::<LittleEndian>().at("fmt chunk", offset));
+        let byte_rate = try!(r.read_u32::<LittleEndian>().at("fmt chunk", offset));
+        let block_align = try!(r.read_u16::<LittleEndian>().at("fmt chunk", offset));
+        let bits_per_sample = try!(r.read_u16::<LittleEndian>().at("fmt chunk", offset));
+
+        if audio_fmt != WAVE_FORMAT_PCM && audio_fmt != WAVE_FORMAT_IEEE_FLOAT && audio_fmt != WAVE_FORMAT_EXTENSIBLE {
+            return Err(SurfboardError::Unsupported { offset: offset, what: "audio format", code: audio_fmt as u32 });
+        }
+        if size < 16 || num_channels == 0 || byte_rate == 0 {
+            return Err(SurfboardError::Malformed { offset: offset, what: "fmt chunk" });
+        }
+
+        let mut extensible = None;
+        if audio_fmt == WAVE_FORMAT_EXTENSIBLE {
+            let cb_size = try!(r.read_u16::<LittleEndian>().at("fmt chunk", offset));
+            if cb_size < 22 || size < 40 {
+                return Err(SurfboardError::Malformed { offset: offset, what: "extensible fmt chunk" });
+            }
+            let valid_bits_per_sample = try!(r.read_u16::<LittleEndian>().at("fmt chunk", offset));
+            let channel_mask = try!(r.read_u32::<LittleEndian>().at("fmt chunk", offset));
+            let mut sub_format = [0; 16];
+            try!(r.read_exact(&mut sub_format).at("fmt chunk", offset));
+            extensible = Some(Extensible {
+                valid_bits_per_sample: valid_bits_per_sample,
+                channel_mask: ChannelMask(channel_mask),
+                sub_format: sub_format,
+            });
+        }
+        // Skip cbSize and anything else past the fields read
+        try!(r.seek(io::SeekFrom::Start(offset + 8 + size as u64)));
+
+        Ok(Fmt {
             id: id,
             size: size,
             audio_fmt: audio_fmt,
@@ -67,188 +231,1340 @@ impl Fmt {
             byte_rate: byte_rate,
             block_align: block_align,
             bits_per_sample: bits_per_sample,
-        }
+            extensible: extensible,
+        })
     }
 }
 
-struct Data {
-    id: u32,
-    size: u32,
-    data: Vec<u8>,
+#[derive(Debug)]
+pub struct Data {
+    pub id: u32,
+    pub size: u32,
+    pub data: Vec<u8>,
 }
 
 impl Data {
-    fn write<W: io::Write>(w: &mut W, data: Vec<u8>) {
+    pub fn write<W: io::Write>(w: &mut W, data: &[u8]) -> Result<()> {
         let id = BigEndian::read_u32(b"data");
 
-        w.write_u32::<BigEndian>(id).unwrap();
-        w.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+        try!(w.write_u32::<BigEndian>(id));
+        try!(w.write_u32::<LittleEndian>(data.len() as u32));
+        try!(w.write_all(data));
+        // Chunks are word aligned
+        if data.len() % 2 != 0 {
+            try!(w.write_u8(0));
+        }
+        Ok(())
     }
 
-    fn parse<R: io::Read>(r: &mut R) -> Data {
+    pub fn parse<R: io::Read + io::Seek>(r: &mut R) -> Result<Data> {
+        let offset = try!(tell(r));
+        try!(expect_id(r, "data", offset));
         let id = BigEndian::read_u32(b"data");
-        let size = r.read_u32::<LittleEndian>().unwrap();
+        let mut size = try!(r.read_u32::<LittleEndian>().at("data chunk", offset));
         let mut data = Vec::new();
-        r.read_to_end(&mut data).unwrap();
+        if size == UNKNOWN_SIZE {
+            // Streams written to pipes run to the end of the file
+            try!(r.read_to_end(&mut data));
+            size = data.len() as u32;
+        } else {
+            try!(r.take(size as u64).read_to_end(&mut data));
+        }
 
-        println!("data size: {}", size);
+        if data.len() < size as usize {
+            return Err(SurfboardError::Truncated { offset: offset, what: "data chunk" });
+        }
 
-        Data {
+        Ok(Data {
             id: id,
             size: size,
             data: data,
-        }
+        })
     }
 }
 
-struct Info {
-    data: HashMap<String, String>,
+#[derive(Debug, Clone, PartialEq)]
+pub struct Info {
+    pub data: HashMap<String, String>,
 }
 
 impl Info {
-    fn parse<R: io::Read + io::Seek>(r: &mut R, size: u32) -> Info {
+    /// Size of the INFO list body, including the `INFO` type id.
+    pub fn size(&self) -> u32 {
+        self.data.values().fold(4, |size, text| {
+            // Text is NUL terminated and padded to an even length
+            let len = text.len() as u32 + 1;
+            size + 8 + len + len % 2
+        })
+    }
+
+    /// Writes a complete `LIST` chunk holding these entries.
+    pub fn write<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(w.write_all(b"LIST"));
+        try!(w.write_u32::<LittleEndian>(self.size()));
+        try!(w.write_all(b"INFO"));
+
+        let mut keys: Vec<&String> = self.data.keys().collect();
+        keys.sort();
+        for key in keys {
+            let text = &self.data[key];
+            let mut id = [b' '; 4];
+            for (dst, src) in id.iter_mut().zip(key.bytes()) {
+                *dst = src;
+            }
+            let len = text.len() as u32 + 1;
+            try!(w.write_all(&id));
+            try!(w.write_u32::<LittleEndian>(len));
+            try!(w.write_all(text.as_bytes()));
+            try!(w.write_u8(0));
+            if len % 2 != 0 {
+                try!(w.write_u8(0));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse<R: io::Read + io::Seek>(r: &mut R, size: u32) -> Result<Info> {
+        // Counted in u64 so a huge entry size cannot wrap around
+        let size = size as u64;
         let mut cur_pos = 4;
         let mut data = HashMap::new();
 
         while cur_pos < size {
-            let mut t = vec![];
-            let info_flag = r.read_u32::<LittleEndian>().unwrap();
-            let text_size = r.read_u32::<LittleEndian>().unwrap();
+            let offset = try!(tell(r));
+            let info_flag = try!(read_id(r, "INFO entry", offset));
+            let text_size = try!(r.read_u32::<LittleEndian>().at("INFO entry", offset));
+
+            if cur_pos + 8 + text_size as u64 > size {
+                return Err(SurfboardError::Malformed { offset: offset, what: "INFO entry" });
+            }
 
-            t.write_u32::<LittleEndian>(info_flag).unwrap();
-            let info_flag = String::from_utf8(t).unwrap();
+            let info_flag = String::from_utf8_lossy(&info_flag).into_owned();
 
-            let mut text = String::new();
+            let mut text = Vec::new();
             {
                 let mut str_handle = r.take(text_size as u64);
-                str_handle.read_to_string(&mut text).unwrap();
-
+                try!(str_handle.read_to_end(&mut text));
             }
-
-            println!("{}: {}", info_flag, text);
+            if text.len() < text_size as usize {
+                return Err(SurfboardError::Truncated { offset: offset, what: "INFO entry" });
+            }
+            // The NUL terminator is not part of the text
+            let text = String::from_utf8_lossy(&text).trim_right_matches('\0').to_string();
 
             data.insert(info_flag, text);
 
-            cur_pos += 8 + text_size;
+            cur_pos += 8 + text_size as u64;
 
             // Handles word alignment cases
             if cur_pos % 2 != 0 {
-                r.seek(io::SeekFrom::Current((cur_pos % 2) as i64)).unwrap();
+                try!(r.seek(io::SeekFrom::Current((cur_pos % 2) as i64)));
                 cur_pos += cur_pos % 2;
             }
         }
-        Info {
+        Ok(Info {
             data: data,
-        }
+        })
     }
 }
 
-struct List {
-    list_id: u32,
-    size: u32,
-    type_id: u32,
-    info: Option<Info>,
+#[derive(Debug)]
+pub struct List {
+    pub list_id: u32,
+    pub size: u32,
+    pub type_id: u32,
+    pub info: Option<Info>,
 }
 
 impl List {
-    fn parse<R: io::Read + io::Seek>(r: &mut R) -> List {
+    pub fn parse<R: io::Read + io::Seek>(r: &mut R) -> Result<List> {
+        let offset = try!(tell(r));
+        try!(expect_id(r, "LIST", offset));
         let list_id = BigEndian::read_u32(b"list");
-        let size = r.read_u32::<LittleEndian>().unwrap();
+        let size = try!(r.read_u32::<LittleEndian>().at("LIST chunk", offset));
 
-        println!("list size: {}", size);
-
-        let mut t = vec![];
-
-        let type_id = r.read_u32::<LittleEndian>().unwrap();
-        t.write_u32::<LittleEndian>(type_id).unwrap();
-        let type_string = String::from_utf8(t).unwrap();
+        if size < 4 {
+            return Err(SurfboardError::Malformed { offset: offset, what: "LIST chunk" });
+        }
 
-        println!("type id: {}", &*type_string);
+        let type_id = try!(r.read_u32::<LittleEndian>().at("LIST chunk", offset));
 
         let mut info = None;
-        if &*type_string == "INFO" {
-            info = Some(Info::parse(r, size));
+        if type_id == LittleEndian::read_u32(b"INFO") {
+            info = Some(try!(Info::parse(r, size)));
         } else {
-            r.seek(io::SeekFrom::Current((size - 4) as i64)).unwrap();
+            try!(r.seek(io::SeekFrom::Current((size - 4) as i64)));
         }
 
-        List {
+        Ok(List {
             list_id: list_id,
             size: size,
             type_id: type_id,
             info: info,
+        })
+    }
+}
+
+/// How samples are stored in the data chunk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleFormat {
+    /// Integer PCM of the given bit depth, 1 to 32. Samples narrower than
+    /// their whole bytes sit in the high bits, and 8-bit samples are unsigned.
+    Int(u16),
+    /// IEEE float, 32 or 64 bits.
+    Float(u16),
+}
+
+impl SampleFormat {
+    fn audio_fmt(&self) -> u16 {
+        match *self {
+            SampleFormat::Int(_) => WAVE_FORMAT_PCM,
+            SampleFormat::Float(_) => WAVE_FORMAT_IEEE_FLOAT,
+        }
+    }
+
+    pub fn bits_per_sample(&self) -> u16 {
+        match *self {
+            SampleFormat::Int(bits) | SampleFormat::Float(bits) => bits,
         }
     }
+
+    /// Bytes each sample takes in the data chunk.
+    pub fn bytes_per_sample(&self) -> u16 {
+        (self.bits_per_sample() + 7) / 8
+    }
 }
 
-pub struct Wave {
-    chunk_size: u32,
-    format: u32,
-    fmt: Fmt,
-    list: Option<List>,
-    data: Data,
+/// Writes WAV files. Defaults to 16-bit stereo at 44100 Hz without tags.
+///
+/// Files with more than two channels, more than 16 bits per sample or a
+/// channel mask get a WAVE_FORMAT_EXTENSIBLE fmt chunk, as the format
+/// requires.
+#[derive(Debug, Clone)]
+pub struct WavWriter {
+    num_channels: u16,
+    sample_rate: u32,
+    format: SampleFormat,
+    channel_mask: Option<ChannelMask>,
+    info: Option<Info>,
+    chunks: Vec<RawChunk>,
+    trailing_chunks: Vec<RawChunk>,
 }
 
-impl Wave {
-    pub fn write<W: io::Write>(w: &mut W, num_channels: u16, sample_rate: u32, bits_per_sample: u16, data: Vec<u8>) {
-        let format = BigEndian::read_u32(b"WAVE");
-        let chunk_size = 4 + 16 + (data.len()); // Filesize - (chunk_id + chunk_size)
+impl WavWriter {
+    pub fn new() -> WavWriter {
+        WavWriter {
+            num_channels: 2,
+            sample_rate: 44100,
+            format: SampleFormat::Int(16),
+            channel_mask: None,
+            info: None,
+            chunks: Vec::new(),
+            trailing_chunks: Vec::new(),
+        }
+    }
+
+    pub fn channels(mut self, num_channels: u16) -> WavWriter {
+        self.num_channels = num_channels;
+        self
+    }
 
-        w.write_u32::<LittleEndian>(chunk_size as u32).unwrap();
-        w.write_u32::<BigEndian>(format).unwrap();
+    pub fn sample_rate(mut self, sample_rate: u32) -> WavWriter {
+        self.sample_rate = sample_rate;
+        self
+    }
 
-        Fmt::write(w, num_channels, sample_rate, bits_per_sample);
-        Data::write(w, data);
+    pub fn sample_format(mut self, format: SampleFormat) -> WavWriter {
+        self.format = format;
+        self
     }
 
-    pub fn parse<R: io::Read + io::Seek>(r: &mut R) -> Wave {
-        let chunk_size = r.read_u32::<LittleEndian>().unwrap();
-        let format = r.read_u32::<LittleEndian>().unwrap();
+    /// The speakers the channels are for. Without one, extensible files get
+    /// the usual layout for their channel count.
+    pub fn channel_mask(mut self, mask: ChannelMask) -> WavWriter {
+        self.channel_mask = Some(mask);
+        self
+    }
 
-        println!("size: {}", chunk_size);
+    /// Tags to write as a LIST/INFO chunk ahead of the audio.
+    pub fn info(mut self, info: Info) -> WavWriter {
+        self.info = if info.data.is_empty() { None } else { Some(info) };
+        self
+    }
 
-        let mut t = vec![];
+    /// Adds a chunk to write ahead of the audio, such as `fact` or `bext`.
+    pub fn chunk(mut self, chunk: RawChunk) -> WavWriter {
+        self.chunks.push(chunk);
+        self
+    }
 
-        t.write_u32::<LittleEndian>(format).unwrap();
-        println!("format: {}", str::from_utf8(&t).unwrap());
-        t.clear();
+    /// Adds a chunk to write after the audio, such as `id3 `.
+    pub fn trailing_chunk(mut self, chunk: RawChunk) -> WavWriter {
+        self.trailing_chunks.push(chunk);
+        self
+    }
 
-        let fmt_chunk = Fmt::parse(r);
+    /// Bytes per frame, one sample for every channel.
+    pub fn block_align(&self) -> u32 {
+        self.num_channels as u32 * self.format.bytes_per_sample() as u32
+    }
 
-        let t_id = r.read_u32::<LittleEndian>().unwrap();
-        t.write_u32::<LittleEndian>(t_id).unwrap();
-        let mut id = String::from_utf8(t).unwrap();
+    fn byte_rate(&self) -> u64 {
+        self.sample_rate as u64 * self.block_align() as u64
+    }
 
-        let mut list_chunk = None;
-        while &*id != "data" {
-            match &*id {
-                "LIST" => {
-                    println!("\nlist id: {}", id);
-                    list_chunk = Some(List::parse(r));
-                },
-                _ => { panic!("Error: cannot parse: {} chunk", id); },
+    fn check(&self) -> Result<()> {
+        let supported = match self.format {
+            SampleFormat::Int(bits) => bits >= 1 && bits <= 32,
+            SampleFormat::Float(bits) => bits == 32 || bits == 64,
+        };
+        if !supported {
+            return Err(SurfboardError::Unsupported { offset: 0, what: "bits per sample", code: self.format.bits_per_sample() as u32 });
+        }
+        if self.num_channels == 0 || self.sample_rate == 0 {
+            return Err(SurfboardError::Malformed { offset: 0, what: "WAV format" });
+        }
+        // Both are stored in fixed size fields of the fmt chunk
+        if self.block_align() > 0xFFFF || self.byte_rate() > 0xFFFFFFFF {
+            return Err(SurfboardError::Malformed { offset: 0, what: "WAV frame size or byte rate" });
+        }
+        Ok(())
+    }
+
+    /// The fmt chunk to write.
+    pub fn fmt(&self) -> Fmt {
+        let bits = self.format.bits_per_sample();
+        let extensible = if self.num_channels > 2 || bits > 16 || self.channel_mask.is_some() {
+            Some(Extensible {
+                valid_bits_per_sample: bits,
+                channel_mask: self.channel_mask.unwrap_or(ChannelMask::for_channels(self.num_channels)),
+                sub_format: Extensible::sub_format_for(self.format.audio_fmt()),
+            })
+        } else {
+            None
+        };
+
+        Fmt {
+            id: LittleEndian::read_u32(b"fmt "),
+            size: if extensible.is_some() { 40 } else { 16 },
+            audio_fmt: if extensible.is_some() { WAVE_FORMAT_EXTENSIBLE } else { self.format.audio_fmt() },
+            num_channels: self.num_channels,
+            sample_rate: self.sample_rate,
+            byte_rate: self.byte_rate() as u32,
+            block_align: self.block_align() as u16,
+            // Extensible files give the whole bytes and their valid bits apart
+            bits_per_sample: if extensible.is_some() { self.format.bytes_per_sample() * 8 } else { bits },
+            extensible: extensible,
+        }
+    }
+
+    /// Length of everything ahead of the audio payload.
+    fn header_size(&self) -> u64 {
+        let chunks = self.chunks.iter().fold(0, |size, chunk| size + chunk.size());
+        12 + 8 + self.fmt().size as u64 + self.info.as_ref().map_or(0, |info| 8 + info.size() as u64) + chunks + 8
+    }
+
+    /// Writes the RIFF header, fmt chunk, INFO tags and data chunk header.
+    fn write_header<W: io::Write>(&self, w: &mut W, chunk_size: u32, data_size: u32) -> Result<()> {
+        try!(w.write_all(b"RIFF"));
+        try!(w.write_u32::<LittleEndian>(chunk_size));
+        try!(w.write_all(b"WAVE"));
+
+        try!(self.fmt().write(w));
+        if let Some(ref info) = self.info {
+            try!(info.write(w));
+        }
+        for chunk in self.chunks.iter() {
+            try!(chunk.write(w));
+        }
+        try!(w.write_all(b"data"));
+        try!(w.write_u32::<LittleEndian>(data_size));
+        Ok(())
+    }
+
+    /// RIFF chunk size of a file holding `data_size` bytes of audio, if it
+    /// fits in 32 bits.
+    fn chunk_size(&self, data_size: u64) -> Result<u32> {
+        // Filesize - (chunk_id + chunk_size)
+        let trailing = self.trailing_chunks.iter().fold(0, |size, chunk| size + chunk.size());
+        let chunk_size = self.header_size() - 8 + data_size + data_size % 2 + trailing;
+        if chunk_size >= UNKNOWN_SIZE as u64 {
+            return Err(SurfboardError::Unsupported { offset: 0, what: "WAV data size over 4 GiB", code: UNKNOWN_SIZE });
+        }
+        Ok(chunk_size as u32)
+    }
+
+    /// Writes a complete file around `data`, which must hold whole frames
+    /// already in the sample format.
+    pub fn write<W: io::Write>(&self, w: &mut W, data: &[u8]) -> Result<()> {
+        try!(self.check());
+        if data.len() % self.block_align() as usize != 0 {
+            return Err(SurfboardError::Malformed { offset: 0, what: "partial frame in WAV data" });
+        }
+        let chunk_size = try!(self.chunk_size(data.len() as u64));
+        try!(self.write_header(w, chunk_size, data.len() as u32));
+        try!(w.write_all(data));
+        // Chunks are word aligned
+        if data.len() % 2 != 0 {
+            try!(w.write_u8(0));
+        }
+        self.write_trailing_chunks(w)
+    }
+
+    fn write_trailing_chunks<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        for chunk in self.trailing_chunks.iter() {
+            try!(chunk.write(w));
+        }
+        Ok(())
+    }
+
+    /// Writes a complete file of interleaved integer samples, given at the
+    /// bit depth of the sample format.
+    pub fn write_samples<W: io::Write>(&self, w: &mut W, samples: &[i32]) -> Result<()> {
+        let mut data = Vec::new();
+        try!(self.encode_samples(samples, &mut data));
+        self.write(w, &data)
+    }
+
+    /// Writes a complete file of interleaved float samples.
+    pub fn write_floats<W: io::Write>(&self, w: &mut W, samples: &[f64]) -> Result<()> {
+        let mut data = Vec::new();
+        try!(self.encode_floats(samples, &mut data));
+        self.write(w, &data)
+    }
+
+    /// Starts a file on `w` to be written a few samples at a time. The sizes
+    /// in the header are patched once the stream is finalized.
+    pub fn start<W: io::Write + io::Seek>(&self, mut w: W) -> Result<WavStream<W>> {
+        let start = try!(w.seek(io::SeekFrom::Current(0)));
+        self.start_stream(w, Some(<W as io::Seek>::seek as SeekFn<W>), start)
+    }
+
+    /// Starts a file on a pipe or other writer that cannot seek back. Both
+    /// sizes in the header are left as 0xFFFFFFFF, which readers take to mean
+    /// the audio runs to the end of the file, so there can be no trailing
+    /// chunks.
+    pub fn start_unseekable<W: io::Write>(&self, w: W) -> Result<WavStream<W>> {
+        if !self.trailing_chunks.is_empty() {
+            return Err(SurfboardError::Unsupported { offset: 0, what: "chunks after streamed audio", code: self.trailing_chunks.len() as u32 });
+        }
+        self.start_stream(w, None, 0)
+    }
+
+    fn start_stream<W: io::Write>(&self, mut w: W, seek: Option<SeekFn<W>>, start: u64) -> Result<WavStream<W>> {
+        try!(self.check());
+        try!(self.write_header(&mut w, UNKNOWN_SIZE, UNKNOWN_SIZE));
+        Ok(WavStream {
+            w: Some(w),
+            seek: seek,
+            writer: self.clone(),
+            start: start,
+            data_size: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    fn encode_samples(&self, samples: &[i32], data: &mut Vec<u8>) -> Result<()> {
+        let bits = match self.format {
+            SampleFormat::Int(bits) => bits,
+            SampleFormat::Float(bits) => return Err(SurfboardError::Unsupported { offset: 0, what: "integer samples as float", code: bits as u32 }),
+        };
+        try!(self.check());
+        let limit = 1i64 << (bits - 1);
+        if samples.iter().any(|&sample| (sample as i64) < -limit || sample as i64 >= limit) {
+            return Err(SurfboardError::Malformed { offset: 0, what: "sample out of range for bits per sample" });
+        }
+        let bytes = self.format.bytes_per_sample() as usize;
+        let shift = bytes * 8 - bits as usize;
+
+        data.reserve(samples.len() * bytes);
+        for &sample in samples {
+            let sample = sample << shift;
+            if bytes == 1 {
+                data.push((sample + 128) as u8);
+            } else {
+                for b in 0..bytes {
+                    data.push((sample >> (8 * b)) as u8);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_floats(&self, samples: &[f64], data: &mut Vec<u8>) -> Result<()> {
+        data.reserve(samples.len() * self.format.bytes_per_sample() as usize);
+        for &sample in samples {
+            match self.format {
+                SampleFormat::Float(32) => try!(data.write_f32::<LittleEndian>(sample as f32)),
+                SampleFormat::Float(64) => try!(data.write_f64::<LittleEndian>(sample)),
+                _ => return Err(SurfboardError::Unsupported { offset: 0, what: "float samples as integer format", code: self.format.bits_per_sample() as u32 }),
+            }
+        }
+        Ok(())
+    }
+}
+
+type SeekFn<W> = fn(&mut W, io::SeekFrom) -> io::Result<u64>;
+
+/// A WAV file being written a few samples at a time, started by
+/// `WavWriter::start` or `WavWriter::start_unseekable`.
+///
+/// `finalize` pads the data chunk and fills in the sizes. Dropping the stream
+/// does the same but has to ignore any errors.
+pub struct WavStream<W: io::Write> {
+    w: Option<W>,
+    /// How to seek back to the header, when `w` can.
+    seek: Option<SeekFn<W>>,
+    writer: WavWriter,
+    /// Where the `RIFF` tag was written.
+    start: u64,
+    data_size: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: io::Write> WavStream<W> {
+    /// Appends interleaved integer samples, which must make up whole frames.
+    pub fn write_samples(&mut self, samples: &[i32]) -> Result<()> {
+        let mut buf = mem::replace(&mut self.buf, Vec::new());
+        buf.clear();
+        let result = self.writer.encode_samples(samples, &mut buf).and_then(|_| self.write(&buf));
+        self.buf = buf;
+        result
+    }
+
+    /// Appends interleaved float samples, which must make up whole frames.
+    pub fn write_floats(&mut self, samples: &[f64]) -> Result<()> {
+        let mut buf = mem::replace(&mut self.buf, Vec::new());
+        buf.clear();
+        let result = self.writer.encode_floats(samples, &mut buf).and_then(|_| self.write(&buf));
+        self.buf = buf;
+        result
+    }
+
+    /// Appends whole frames already in the sample format.
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() % self.writer.block_align() as usize != 0 {
+            return Err(SurfboardError::Malformed { offset: 0, what: "partial frame in WAV data" });
+        }
+        let data_size = self.data_size + data.len() as u64;
+        if self.seek.is_some() {
+            try!(self.writer.chunk_size(data_size));
+        }
+        try!(self.w.as_mut().unwrap().write_all(data));
+        self.data_size = data_size;
+        Ok(())
+    }
+
+    /// Bytes of audio written so far.
+    pub fn data_size(&self) -> u64 {
+        self.data_size
+    }
+
+    /// Finishes the file and hands back the writer, positioned after it.
+    pub fn finalize(mut self) -> Result<W> {
+        // Taken first so that `drop` does not finish again after an error
+        let mut w = self.w.take().unwrap();
+        try!(self.finish(&mut w));
+        Ok(w)
+    }
+
+    fn finish(&self, w: &mut W) -> Result<()> {
+        if let Some(seek) = self.seek {
+            // Chunks are word aligned, but a pad byte at the end of a data
+            // chunk of unknown size would be read as audio
+            if self.data_size % 2 != 0 {
+                try!(w.write_u8(0));
             }
+            try!(self.writer.write_trailing_chunks(w));
+
+            let chunk_size = try!(self.writer.chunk_size(self.data_size));
+            let end = self.start + 8 + chunk_size as u64;
+            try!(seek(w, io::SeekFrom::Start(self.start + 4)));
+            try!(w.write_u32::<LittleEndian>(chunk_size));
+            try!(seek(w, io::SeekFrom::Start(self.start + self.writer.header_size() - 4)));
+            try!(w.write_u32::<LittleEndian>(self.data_size as u32));
+            try!(seek(w, io::SeekFrom::Start(end)));
+        }
+        try!(w.flush());
+        Ok(())
+    }
+}
 
-            let t_id = r.read_u32::<LittleEndian>().unwrap();
-            let mut tmp = Vec::new();
-            tmp.write_u32::<LittleEndian>(t_id).unwrap();
-            id = String::from_utf8(tmp).unwrap();
+impl<W: io::Write> Drop for WavStream<W> {
+    fn drop(&mut self) {
+        if let Some(mut w) = self.w.take() {
+            let _ = self.finish(&mut w);
         }
+    }
+}
+
+#[derive(Debug)]
+pub struct Wave {
+    pub chunk_size: u32,
+    pub format: u32,
+    pub fmt: Fmt,
+    pub list: Option<List>,
+    pub data: Data,
+    /// Other chunks ahead of the audio, kept as they were.
+    pub chunks: Vec<RawChunk>,
+    /// Other chunks after the audio, kept as they were.
+    pub trailing_chunks: Vec<RawChunk>,
+}
+
+impl Wave {
+    /// Parses a WAV file, starting from its `RIFF` tag. Chunks may come in
+    /// any order, and those this crate does not know are kept as raw bytes.
+    pub fn parse<R: io::Read + io::Seek>(r: &mut R) -> Result<Wave> {
+        let (mut chunks, chunk_size) = try!(Chunks::new(r, "WAVE"));
+        let mut fmt_chunk = None;
+        let mut list_chunk = None;
+        let mut data_chunk = None;
+        let mut other_chunks = Vec::new();
+        let mut trailing_chunks = Vec::new();
 
-        println!("\ndata id: {}", id);
-        let data_chunk = Data::parse(r);
+        while let Some(header) = chunks.next() {
+            let header = try!(header);
+            let raw = match &header.id {
+                b"fmt " => { fmt_chunk = Some(try!(Fmt::parse(chunks.get_mut()))); None },
+                b"data" => { data_chunk = Some(try!(Data::parse(chunks.get_mut()))); None },
+                b"LIST" => {
+                    let list = try!(List::parse(chunks.get_mut()));
+                    if list.info.is_some() {
+                        list_chunk = Some(list);
+                        None
+                    } else {
+                        Some(try!(RawChunk::parse(&mut chunks, &header)))
+                    }
+                },
+                _ => Some(try!(RawChunk::parse(&mut chunks, &header))),
+            };
+            if let Some(raw) = raw {
+                if data_chunk.is_some() {
+                    trailing_chunks.push(raw);
+                } else {
+                    other_chunks.push(raw);
+                }
+            }
+        }
 
-        let song_ms = ((data_chunk.size as u64) * 1000) / fmt_chunk.byte_rate as u64;
-        let song_min = song_ms / (60 * 1000);
-        let song_sec = song_ms / 1000;
-        println!("\nSong length: {}:{:0>2}", song_min, song_sec - (song_min * 60));
+        let fmt_chunk = match fmt_chunk {
+            Some(fmt) => fmt,
+            None => return Err(SurfboardError::Malformed { offset: 12, what: "WAV file without fmt chunk" }),
+        };
+        let data_chunk = match data_chunk {
+            Some(data) => data,
+            None => return Err(SurfboardError::Malformed { offset: 12, what: "WAV file without data chunk" }),
+        };
 
-        Wave {
+        Ok(Wave {
             chunk_size: chunk_size,
-            format: format,
+            format: LittleEndian::read_u32(b"WAVE"),
             fmt: fmt_chunk,
             list: list_chunk,
             data: data_chunk,
+            chunks: other_chunks,
+            trailing_chunks: trailing_chunks,
+        })
+    }
+
+    /// A writer for this file's format, tags and other chunks, to write it
+    /// back out with `write(w, &wave.data.data)`.
+    pub fn writer(&self) -> Result<WavWriter> {
+        let format = match self.fmt.sample_format() {
+            Some(format) => format,
+            None => return Err(SurfboardError::Unsupported { offset: 12, what: "sample format", code: self.fmt.bits_per_sample as u32 }),
+        };
+        let mut writer = WavWriter::new()
+            .channels(self.fmt.num_channels)
+            .sample_rate(self.fmt.sample_rate)
+            .sample_format(format);
+        if let Some(info) = self.list.as_ref().and_then(|l| l.info.as_ref()) {
+            writer = writer.info(info.clone());
+        }
+        writer.chunks = self.chunks.clone();
+        writer.trailing_chunks = self.trailing_chunks.clone();
+        Ok(writer)
+    }
+
+    /// Length of the audio payload in milliseconds.
+    pub fn length_ms(&self) -> u64 {
+        ((self.data.size as u64) * 1000) / self.fmt.byte_rate as u64
+    }
+}
+
+/// A type samples can be read as.
+pub trait Sample: Sized {
+    /// Whether samples of `format` can be read as this type without loss.
+    fn supports(format: SampleFormat) -> bool;
+
+    /// Decodes one little endian sample of `format`.
+    fn decode(bytes: &[u8], format: SampleFormat) -> Self;
+}
+
+/// Decodes an integer sample, moving it down from the high bits of its
+/// bytes. 8-bit samples are unsigned.
+fn decode_int(bytes: &[u8], bits: u16) -> i32 {
+    if bytes.len() == 1 {
+        return (bytes[0] as i32 - 128) >> (8 - bits);
+    }
+    let width = bytes.len() * 8;
+    let raw = LittleEndian::read_uint(bytes, bytes.len()) as u32;
+    ((raw << (32 - width)) as i32) >> (32 - bits as usize)
+}
+
+impl Sample for i16 {
+    fn supports(format: SampleFormat) -> bool {
+        match format {
+            SampleFormat::Int(bits) => bits <= 16,
+            SampleFormat::Float(_) => false,
+        }
+    }
+
+    fn decode(bytes: &[u8], format: SampleFormat) -> i16 {
+        decode_int(bytes, format.bits_per_sample()) as i16
+    }
+}
+
+impl Sample for i32 {
+    fn supports(format: SampleFormat) -> bool {
+        match format {
+            SampleFormat::Int(_) => true,
+            SampleFormat::Float(_) => false,
+        }
+    }
+
+    fn decode(bytes: &[u8], format: SampleFormat) -> i32 {
+        decode_int(bytes, format.bits_per_sample())
+    }
+}
+
+impl Sample for f32 {
+    fn supports(format: SampleFormat) -> bool {
+        format == SampleFormat::Float(32)
+    }
+
+    fn decode(bytes: &[u8], _: SampleFormat) -> f32 {
+        LittleEndian::read_f32(bytes)
+    }
+}
+
+impl Sample for f64 {
+    fn supports(format: SampleFormat) -> bool {
+        match format {
+            SampleFormat::Int(_) => false,
+            SampleFormat::Float(_) => true,
+        }
+    }
+
+    fn decode(bytes: &[u8], format: SampleFormat) -> f64 {
+        match format {
+            SampleFormat::Float(32) => LittleEndian::read_f32(bytes) as f64,
+            _ => LittleEndian::read_f64(bytes),
+        }
+    }
+}
+
+/// Reads a WAV file's header up front and its samples only when asked,
+/// stopping at the end of the data chunk.
+pub struct WavReader<R> {
+    pub chunk_size: u32,
+    pub fmt: Fmt,
+    pub list: Option<List>,
+    format: SampleFormat,
+    bytes_per_sample: u16,
+    r: R,
+    /// Where the audio payload starts.
+    data_offset: u64,
+    /// Bytes of audio, or `None` when the file was streamed to a pipe and
+    /// the audio runs to the end of it.
+    data_size: Option<u64>,
+    /// Bytes of audio read so far.
+    position: u64,
+}
+
+impl<R: io::Read + io::Seek> WavReader<R> {
+    pub fn new(r: R) -> Result<WavReader<R>> {
+        let (mut chunks, chunk_size) = try!(Chunks::new(r, "WAVE"));
+        let mut fmt = None;
+        let mut list = None;
+        let mut data = None;
+        // Walk every chunk, since tags may follow the audio. The audio itself
+        // is skipped over rather than read.
+        while let Some(header) = chunks.next() {
+            let header = try!(header);
+            match &header.id {
+                b"fmt " => fmt = Some(try!(Fmt::parse(chunks.get_mut()))),
+                b"LIST" => {
+                    let chunk = try!(List::parse(chunks.get_mut()));
+                    if chunk.info.is_some() {
+                        list = Some(chunk);
+                    }
+                },
+                b"data" if data.is_none() => data = Some(header),
+                _ => {},
+            }
+        }
+        let fmt = match fmt {
+            Some(fmt) => fmt,
+            None => return Err(SurfboardError::Malformed { offset: 12, what: "WAV file without fmt chunk" }),
+        };
+        let data = match data {
+            Some(data) => data,
+            None => return Err(SurfboardError::Malformed { offset: 12, what: "WAV file without data chunk" }),
+        };
+        let mut r = chunks.into_inner();
+        try!(r.seek(io::SeekFrom::Start(data.data_offset())));
+        let data_size = data.size;
+
+        let format = match fmt.sample_format() {
+            Some(format) => format,
+            None => return Err(SurfboardError::Unsupported { offset: 12, what: "sample format", code: fmt.bits_per_sample as u32 }),
+        };
+        // Extensible files may pad samples out beyond their valid bits
+        let bytes_per_sample = (fmt.bits_per_sample + 7) / 8;
+        if fmt.block_align as u32 != fmt.num_channels as u32 * bytes_per_sample as u32 {
+            return Err(SurfboardError::Malformed { offset: 12, what: "fmt block alignment" });
+        }
+        let block_align = fmt.block_align as u64;
+
+        Ok(WavReader {
+            chunk_size: chunk_size,
+            fmt: fmt,
+            list: list,
+            format: format,
+            bytes_per_sample: bytes_per_sample,
+            r: r,
+            data_offset: data.data_offset(),
+            // A trailing partial frame is ignored
+            data_size: if data_size == UNKNOWN_SIZE { None } else { Some(data_size as u64 - data_size as u64 % block_align) },
+            position: 0,
+        })
+    }
+}
+
+impl<R: io::Read> WavReader<R> {
+    pub fn sample_format(&self) -> SampleFormat {
+        self.format
+    }
+
+    /// Number of samples across all channels, if the header says.
+    pub fn len(&self) -> Option<u64> {
+        self.data_size.map(|size| size / self.bytes_per_sample as u64)
+    }
+
+    /// Number of frames, one sample for every channel, if the header says.
+    pub fn frames_len(&self) -> Option<u64> {
+        self.data_size.map(|size| size / self.fmt.block_align as u64)
+    }
+
+    /// The remaining samples, interleaved, as `S`. Fails if the samples
+    /// do not fit in `S`, such as 24-bit samples as `i16`.
+    pub fn samples<'a, S: Sample>(&'a mut self) -> Result<WavSamples<'a, R, S>> {
+        if !S::supports(self.format) {
+            return Err(SurfboardError::Unsupported { offset: 12, what: "sample type for format", code: self.format.bits_per_sample() as u32 });
+        }
+        Ok(WavSamples {
+            reader: self,
+            sample: PhantomData,
+        })
+    }
+
+    /// The remaining frames, each holding one sample for every channel.
+    pub fn frames<'a, S: Sample>(&'a mut self) -> Result<WavFrames<'a, R, S>> {
+        let num_channels = self.fmt.num_channels as usize;
+        Ok(WavFrames {
+            samples: try!(self.samples()),
+            num_channels: num_channels,
+        })
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    /// Reads the next sample's bytes into `buf`, returning false at the end
+    /// of the audio.
+    fn read_sample(&mut self, buf: &mut [u8]) -> Result<bool> {
+        if let Some(size) = self.data_size {
+            if self.position + buf.len() as u64 > size {
+                return Ok(false);
+            }
+        }
+
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.r.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(SurfboardError::Io(e)),
+            }
+        }
+        // Without a size, the audio ends where the file does
+        if filled == 0 && self.data_size.is_none() {
+            return Ok(false);
+        }
+        if filled < buf.len() {
+            // The audio ends here, after this error
+            let offset = self.data_offset + self.position;
+            self.data_size = Some(self.position);
+            return Err(SurfboardError::Truncated { offset: offset, what: "data chunk" });
+        }
+        self.position += filled as u64;
+        Ok(true)
+    }
+}
+
+/// Iterates over samples as `S`, from `WavReader::samples`.
+pub struct WavSamples<'a, R: 'a, S> {
+    reader: &'a mut WavReader<R>,
+    sample: PhantomData<S>,
+}
+
+impl<'a, R: io::Read, S: Sample> Iterator for WavSamples<'a, R, S> {
+    type Item = Result<S>;
+
+    fn next(&mut self) -> Option<Result<S>> {
+        let mut buf = [0; 8];
+        let format = self.reader.format;
+        let bytes = &mut buf[..self.reader.bytes_per_sample as usize];
+        match self.reader.read_sample(bytes) {
+            Ok(true) => Some(Ok(S::decode(bytes, format))),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Iterates over frames of `S`, from `WavReader::frames`.
+pub struct WavFrames<'a, R: 'a, S> {
+    samples: WavSamples<'a, R, S>,
+    num_channels: usize,
+}
+
+impl<'a, R: io::Read, S: Sample> Iterator for WavFrames<'a, R, S> {
+    type Item = Result<Vec<S>>;
+
+    fn next(&mut self) -> Option<Result<Vec<S>>> {
+        let mut frame = Vec::with_capacity(self.num_channels);
+        while frame.len() < self.num_channels {
+            match self.samples.next() {
+                Some(Ok(sample)) => frame.push(sample),
+                Some(Err(e)) => return Some(Err(e)),
+                None if frame.is_empty() => return None,
+                None => {
+                    let reader = &self.samples.reader;
+                    return Some(Err(SurfboardError::Truncated { offset: reader.data_offset + reader.position, what: "data chunk" }));
+                },
+            }
+        }
+        Some(Ok(frame))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{self, Cursor};
+    use super::{ChannelMask, Extensible, Info, SampleFormat, Speaker, WavReader, WavWriter, Wave};
+    use super::{UNKNOWN_SIZE, WAVE_FORMAT_EXTENSIBLE, WAVE_FORMAT_PCM};
+    use error::SurfboardError;
+    use riff::RawChunk;
+
+    fn parse(bytes: Vec<u8>) -> Wave {
+        let len = bytes.len() as u32;
+        let wave = Wave::parse(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(wave.chunk_size, len - 8);
+        wave
+    }
+
+    #[test]
+    fn round_trips_samples_and_info() {
+        let mut data = HashMap::new();
+        data.insert("INAM".to_string(), "Title".to_string());
+        data.insert("IART".to_string(), "Artist".to_string());
+        let info = Info { data: data };
+        let writer = WavWriter::new().sample_rate(48000).info(info.clone());
+
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &[0, -1, 32767, -32768]).unwrap();
+        let wave = parse(bytes);
+
+        assert_eq!(wave.fmt.audio_fmt, 1);
+        assert_eq!(wave.fmt.num_channels, 2);
+        assert_eq!(wave.fmt.sample_rate, 48000);
+        assert_eq!(wave.fmt.byte_rate, 48000 * 4);
+        assert_eq!(wave.fmt.block_align, 4);
+        assert_eq!(wave.fmt.bits_per_sample, 16);
+        assert_eq!(wave.list.unwrap().info, Some(info));
+        assert_eq!(wave.data.size, 8);
+        assert_eq!(&wave.data.data[..8], &[0, 0, 0xFF, 0xFF, 0xFF, 0x7F, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn pads_odd_data_and_text() {
+        let mut data = HashMap::new();
+        data.insert("ICMT".to_string(), "odd".to_string());
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Int(8)).info(Info { data: data });
+
+        let mut bytes = Vec::new();
+        writer.write(&mut bytes, &[1, 2, 3]).unwrap();
+        assert_eq!(bytes.len() % 2, 0);
+        let wave = parse(bytes);
+
+        assert_eq!(wave.list.unwrap().info.unwrap().data["ICMT"], "odd");
+        assert_eq!(wave.data.size, 3);
+        assert_eq!(&wave.data.data[..3], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_info_entries_past_the_list() {
+        // An entry size that would wrap around a u32 position
+        let bytes = b"INAM\xF8\xFF\xFF\xFFx\x00\x00\x00".to_vec();
+        match Info::parse(&mut Cursor::new(bytes), 16) {
+            Err(SurfboardError::Malformed { what: "INFO entry", .. }) => {},
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn packs_narrow_samples_into_high_bits() {
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Int(12));
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &[1, -2048]).unwrap();
+        let wave = parse(bytes);
+        assert_eq!(wave.fmt.bits_per_sample, 12);
+        assert_eq!(wave.fmt.block_align, 2);
+        assert_eq!(&wave.data.data[..], &[0x10, 0x00, 0x00, 0x80]);
+
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Int(8));
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &[-128, 0, 127, 0]).unwrap();
+        assert_eq!(&parse(bytes).data.data[..], &[0, 128, 255, 128]);
+    }
+
+    #[test]
+    fn writes_float_samples() {
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Float(32));
+        let mut bytes = Vec::new();
+        writer.write_floats(&mut bytes, &[0.5, -1.0]).unwrap();
+        let wave = parse(bytes);
+        assert_eq!(wave.fmt.audio_fmt, WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(wave.fmt.sample_format(), Some(SampleFormat::Float(32)));
+        assert_eq!(&wave.data.data[..], &[0, 0, 0, 0x3F, 0, 0, 0x80, 0xBF]);
+    }
+
+    #[test]
+    fn streams_the_same_file_as_a_single_write() {
+        let mut data = HashMap::new();
+        data.insert("INAM".to_string(), "Title".to_string());
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Int(24)).info(Info { data: data });
+        let samples: Vec<i32> = (0..1001).map(|i| i * 1000 - 500000).collect();
+
+        let mut expected = Vec::new();
+        writer.write_samples(&mut expected, &samples).unwrap();
+
+        let mut stream = writer.start(Cursor::new(Vec::new())).unwrap();
+        for chunk in samples.chunks(300) {
+            stream.write_samples(chunk).unwrap();
+        }
+        assert_eq!(stream.data_size(), 3003);
+        let cursor = stream.finalize().unwrap();
+        assert_eq!(cursor.position(), expected.len() as u64);
+        assert_eq!(cursor.into_inner(), expected);
+
+        let mut cursor = Cursor::new(Vec::new());
+        {
+            let mut stream = writer.start(&mut cursor).unwrap();
+            stream.write_samples(&samples).unwrap();
+        }
+        assert_eq!(cursor.into_inner(), expected);
+    }
+
+    /// Writes through to a cursor but fails every flush.
+    struct FailingFlush<'a>(&'a mut Cursor<Vec<u8>>);
+
+    impl<'a> io::Write for FailingFlush<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            io::Write::write(self.0, buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::new(io::ErrorKind::Other, "flush failed"))
+        }
+    }
+
+    impl<'a> io::Seek for FailingFlush<'a> {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            io::Seek::seek(self.0, pos)
+        }
+    }
+
+    #[test]
+    fn finishes_once_when_finalize_fails() {
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Int(8))
+            .trailing_chunk(RawChunk { id: *b"id3 ", data: b"tag".to_vec() });
+        let mut expected = Vec::new();
+        writer.write_samples(&mut expected, &[1, 2, 3]).unwrap();
+
+        let mut cursor = Cursor::new(Vec::new());
+        {
+            let mut stream = writer.start(FailingFlush(&mut cursor)).unwrap();
+            stream.write_samples(&[1, 2, 3]).unwrap();
+            assert!(stream.finalize().is_err());
+        }
+        // The pad byte and trailing chunk are not written again on drop
+        assert_eq!(cursor.into_inner(), expected);
+    }
+
+    #[test]
+    fn streams_to_unseekable_writers() {
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Int(8));
+        let mut stream = writer.start_unseekable(Vec::new()).unwrap();
+        stream.write_samples(&[-128, 0]).unwrap();
+        stream.write_samples(&[127]).unwrap();
+        let bytes = stream.finalize().unwrap();
+
+        let wave = Wave::parse(&mut Cursor::new(bytes)).unwrap();
+        assert_eq!(wave.chunk_size, UNKNOWN_SIZE);
+        assert_eq!(wave.data.size, 3);
+        assert_eq!(&wave.data.data[..], &[0, 128, 255]);
+    }
+
+    #[test]
+    fn reads_typed_samples_lazily() {
+        let writer = WavWriter::new().sample_format(SampleFormat::Int(24));
+        let samples = [0, -1, 8388607, -8388608, 12345, -54321];
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &samples).unwrap();
+        // A chunk after the audio is not part of it
+        bytes.extend(b"junk\x04\x00\x00\x00abcd".iter().cloned());
+
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.sample_format(), SampleFormat::Int(24));
+        assert_eq!(reader.len(), Some(6));
+        assert_eq!(reader.frames_len(), Some(3));
+        assert!(reader.samples::<i16>().is_err());
+        {
+            let mut iter = reader.samples::<i32>().unwrap();
+            assert_eq!(iter.next().unwrap().unwrap(), 0);
+            assert_eq!(iter.next().unwrap().unwrap(), -1);
+        }
+        let frames: Vec<Vec<i32>> = reader.frames().unwrap().map(|f| f.unwrap()).collect();
+        assert_eq!(frames, vec![vec![8388607, -8388608], vec![12345, -54321]]);
+        assert_eq!(reader.into_inner().position(), 12 + 48 + 8 + 18);
+    }
+
+    #[test]
+    fn reads_narrow_and_float_samples() {
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Int(12));
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &[2047, -2048, 1]).unwrap();
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        let samples: Vec<i16> = reader.samples().unwrap().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, vec![2047, -2048, 1]);
+
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Int(8));
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &[-128, 127, 0]).unwrap();
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        let samples: Vec<i16> = reader.samples().unwrap().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, vec![-128, 127, 0]);
+
+        let writer = WavWriter::new().channels(1).sample_format(SampleFormat::Float(32));
+        let mut bytes = Vec::new();
+        writer.write_floats(&mut bytes, &[0.25, -1.0]).unwrap();
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        assert!(reader.samples::<i32>().is_err());
+        let samples: Vec<f32> = reader.samples().unwrap().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, vec![0.25, -1.0]);
+    }
+
+    #[test]
+    fn reads_unsized_and_truncated_audio() {
+        let writer = WavWriter::new().channels(1);
+        let mut stream = writer.start_unseekable(Vec::new()).unwrap();
+        stream.write_samples(&[1, 2, 3]).unwrap();
+        let bytes = stream.finalize().unwrap();
+        let mut reader = WavReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(reader.len(), None);
+        let samples: Vec<i32> = reader.samples().unwrap().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, vec![1, 2, 3]);
+
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &[1, 2, 3]).unwrap();
+        let len = bytes.len();
+        bytes.truncate(len - 3);
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        let samples: Vec<_> = reader.samples::<i16>().unwrap().collect();
+        assert_eq!(samples.len(), 2);
+        assert!(samples[1].is_err());
+    }
+
+    #[test]
+    fn keeps_unknown_chunks_in_any_order() {
+        let fact = RawChunk { id: *b"fact", data: vec![2, 0, 0, 0] };
+        let id3 = RawChunk { id: *b"id3 ", data: b"tag".to_vec() };
+        let mut data = HashMap::new();
+        data.insert("INAM".to_string(), "Title".to_string());
+        let writer = WavWriter::new().channels(1).info(Info { data: data })
+            .chunk(fact.clone()).trailing_chunk(id3.clone());
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &[1, -1]).unwrap();
+
+        let wave = parse(bytes.clone());
+        assert_eq!(wave.chunks, vec![fact.clone()]);
+        assert_eq!(wave.trailing_chunks, vec![id3.clone()]);
+        assert_eq!(wave.data.size, 4);
+        assert_eq!(wave.data.data.len(), 4);
+
+        let mut rewritten = Vec::new();
+        wave.writer().unwrap().write(&mut rewritten, &wave.data.data).unwrap();
+        assert_eq!(rewritten, bytes);
+
+        let mut stream = writer.start(Cursor::new(Vec::new())).unwrap();
+        stream.write_samples(&[1, -1]).unwrap();
+        assert_eq!(stream.finalize().unwrap().into_inner(), bytes);
+        assert!(writer.start_unseekable(Vec::new()).is_err());
+
+        // Move fmt after a JUNK chunk and put the LIST after the audio
+        let fmt_start = 12;
+        let list_start = fmt_start + 24;
+        let list_end = list_start + 8 + 4 + 14;
+        let mut moved = bytes[..12].to_vec();
+        moved.extend(b"JUNK\x01\x00\x00\x00j\x00".iter().cloned());
+        moved.extend(bytes[fmt_start..list_start].iter().cloned());
+        moved.extend(bytes[list_end..].iter().cloned());
+        moved.extend(bytes[list_start..list_end].iter().cloned());
+        let len = moved.len() as u32 - 8;
+        moved[4] = len as u8;
+
+        let wave = parse(moved.clone());
+        assert_eq!(wave.fmt.num_channels, 1);
+        assert_eq!(wave.chunks[0].id, *b"JUNK");
+        assert_eq!(wave.trailing_chunks, vec![id3]);
+        assert_eq!(wave.list.unwrap().info.unwrap().data["INAM"], "Title");
+
+        let mut reader = WavReader::new(Cursor::new(moved)).unwrap();
+        assert_eq!(reader.list.as_ref().unwrap().info.as_ref().unwrap().data["INAM"], "Title");
+        let samples: Vec<i16> = reader.samples().unwrap().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, vec![1, -1]);
+    }
+
+    #[test]
+    fn writes_extensible_headers_when_needed() {
+        let mut bytes = Vec::new();
+        WavWriter::new().write_samples(&mut bytes, &[0, 0]).unwrap();
+        let wave = parse(bytes);
+        assert_eq!(wave.fmt.audio_fmt, WAVE_FORMAT_PCM);
+        assert_eq!(wave.fmt.extensible, None);
+        assert_eq!(wave.fmt.channel_mask().speakers(), vec![Speaker::FrontLeft, Speaker::FrontRight]);
+
+        let writer = WavWriter::new().channels(6).sample_format(SampleFormat::Int(20));
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &[-524288, 524287, 1, -1, 0, 3]).unwrap();
+        assert_eq!(&bytes[12..20], b"fmt \x28\x00\x00\x00");
+        let wave = parse(bytes.clone());
+        assert_eq!(wave.fmt.audio_fmt, WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(wave.fmt.bits_per_sample, 24);
+        assert_eq!(wave.fmt.block_align, 18);
+        assert_eq!(wave.fmt.sample_format(), Some(SampleFormat::Int(20)));
+        let ext = wave.fmt.extensible.clone().unwrap();
+        assert_eq!(ext.valid_bits_per_sample, 20);
+        assert_eq!(ext.channel_mask, ChannelMask(0x3F));
+        assert_eq!(ext.sub_format_code(), Some(WAVE_FORMAT_PCM));
+        assert_eq!(&ext.sub_format[..4], &[1, 0, 0, 0]);
+
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        let samples: Vec<i32> = reader.samples().unwrap().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, vec![-524288, 524287, 1, -1, 0, 3]);
+
+        let mask = ChannelMask::from_speakers(&[Speaker::FrontCenter, Speaker::LowFrequency]);
+        let writer = WavWriter::new().channel_mask(mask);
+        let mut bytes = Vec::new();
+        writer.write_samples(&mut bytes, &[0, 0]).unwrap();
+        let wave = parse(bytes);
+        assert_eq!(wave.fmt.channel_mask().speakers(), vec![Speaker::FrontCenter, Speaker::LowFrequency]);
+        assert_eq!(wave.fmt.sample_format(), Some(SampleFormat::Int(16)));
+    }
+
+    #[test]
+    fn reads_extensible_headers_from_other_writers() {
+        // 24 valid bits in 32-bit samples, with a longer fmt chunk than usual
+        let mut bytes = b"RIFF\x00\x00\x00\x00WAVEfmt \x2a\x00\x00\x00".to_vec();
+        bytes.extend([0xFE, 0xFF, 1, 0, 0x44, 0xAC, 0, 0, 0x10, 0xB1, 2, 0, 4, 0, 32, 0, 24, 0, 24, 0].iter().cloned());
+        bytes.extend([4, 0, 0, 0].iter().cloned());
+        bytes.extend(Extensible::sub_format_for(WAVE_FORMAT_PCM).iter().cloned());
+        bytes.extend([0xAA, 0xBB].iter().cloned());
+        bytes.extend(b"data\x08\x00\x00\x00\x00\x01\x00\x80\x00\xFF\xFF\xFF".iter().cloned());
+        let len = bytes.len() as u32 - 8;
+        bytes[4] = len as u8;
+
+        let wave = parse(bytes.clone());
+        assert_eq!(wave.fmt.channel_mask().speakers(), vec![Speaker::FrontCenter]);
+        assert_eq!(wave.data.size, 8);
+
+        let mut reader = WavReader::new(Cursor::new(bytes.clone())).unwrap();
+        assert_eq!(reader.sample_format(), SampleFormat::Int(24));
+        let samples: Vec<i32> = reader.samples().unwrap().map(|s| s.unwrap()).collect();
+        assert_eq!(samples, vec![-8388607, -1]);
+
+        // Integer containers wider than 32 bits are not read
+        for &bits in [64u8, 80].iter() {
+            let mut bytes = bytes.clone();
+            bytes[32] = bits / 8;
+            bytes[34] = bits;
+            match WavReader::new(Cursor::new(bytes)) {
+                Err(SurfboardError::Unsupported { what: "sample format", .. }) => {},
+                other => panic!("{:?}", other.map(|r| r.sample_format())),
+            }
+        }
+
+        let unknown = Extensible { valid_bits_per_sample: 16, channel_mask: ChannelMask(0), sub_format: [7; 16] };
+        assert_eq!(unknown.sub_format_code(), None);
+    }
+
+    #[test]
+    fn rejects_bad_formats_and_partial_frames() {
+        let mut stream = WavWriter::new().start_unseekable(Vec::new()).unwrap();
+        assert!(stream.write_samples(&[0, 0, 0]).is_err());
+        assert!(stream.write_floats(&[0.0, 0.0]).is_err());
+        assert_eq!(stream.data_size(), 0);
+
+        let mut bytes = Vec::new();
+        assert!(WavWriter::new().write(&mut bytes, &[0, 0, 0]).is_err());
+        assert!(WavWriter::new().channels(0).write(&mut bytes, &[]).is_err());
+        assert!(WavWriter::new().sample_format(SampleFormat::Float(16)).write(&mut bytes, &[]).is_err());
+        assert!(WavWriter::new().sample_format(SampleFormat::Float(32)).write_samples(&mut bytes, &[0]).is_err());
+        // Frames and byte rates too large for the fmt chunk
+        assert!(WavWriter::new().channels(40000).write_samples(&mut bytes, &vec![0; 40000]).is_err());
+        assert!(WavWriter::new().channels(40000).start_unseekable(Vec::new()).is_err());
+        let fast = WavWriter::new().channels(8).sample_format(SampleFormat::Int(32)).sample_rate(200000000);
+        assert!(fast.write_samples(&mut bytes, &[0; 8]).is_err());
+        assert!(bytes.is_empty());
+
+        // Samples outside the bit depth, rather than wrapped or clamped
+        let int8 = WavWriter::new().channels(1).sample_format(SampleFormat::Int(8));
+        assert!(int8.write_samples(&mut bytes, &[-128, 127, 0x7FFFFFFF]).is_err());
+        assert!(WavWriter::new().write_samples(&mut bytes, &[0, 70000]).is_err());
+        let narrow = WavWriter::new().channels(1).sample_format(SampleFormat::Int(12));
+        assert!(narrow.write_samples(&mut bytes, &[2048]).is_err());
+        assert!(narrow.write_samples(&mut bytes, &[-2049]).is_err());
+        assert!(bytes.is_empty());
+        let mut stream = WavWriter::new().start_unseekable(Vec::new()).unwrap();
+        assert!(stream.write_samples(&[0, 0, -32769, 0]).is_err());
+        assert_eq!(stream.data_size(), 0);
+        narrow.write_samples(&mut bytes, &[-2048, 2047]).unwrap();
+        bytes.clear();
+
+        // A frame of 65535 16-bit channels is larger than the block
+        // alignment field can hold
+        WavWriter::new().write_samples(&mut bytes, &[0, 0]).unwrap();
+        bytes[22] = 0xFF;
+        bytes[23] = 0xFF;
+        match WavReader::new(Cursor::new(bytes)) {
+            Err(SurfboardError::Malformed { what: "fmt block alignment", .. }) => {},
+            other => panic!("{:?}", other.map(|r| r.sample_format())),
         }
     }
 }